@@ -0,0 +1,73 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+const FANJIAN_DATA: &str = include_str!("../../data/char/fanjian.txt");
+const YITI_DATA: &str = include_str!("../../data/char/yiti.txt");
+
+static FANJIAN: LazyLock<CharMap> =
+    LazyLock::new(|| CharMap::parse(FANJIAN_DATA).expect("内置繁简对照表格式错误"));
+static YITI: LazyLock<CharMap> =
+    LazyLock::new(|| CharMap::parse(YITI_DATA).expect("内置异体字对照表格式错误"));
+
+/// 字形对照表：韵书中查不到某字时，改按对照的字形查找
+#[derive(Clone, Debug, Default)]
+pub struct CharMap(HashMap<char, char>);
+
+impl CharMap {
+    /// 解析对照表：每行一对字，以空白分隔，前为待转换的字形，后为韵书所用字形，# 后为注释
+    pub fn parse(content: &str) -> Result<CharMap> {
+        let mut map = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let chars: Vec<Option<char>> = fields.iter().map(|f| single_char(f)).collect();
+            match chars.as_slice() {
+                [Some(from), Some(to)] if from != to => { map.insert(*from, *to); }
+                _ => bail!("第 {} 行格式错误，应为以空白分隔的两个不同的字：{}", i + 1, line),
+            }
+        }
+        Ok(CharMap(map))
+    }
+
+    pub fn get(&self, c: &char) -> Option<char> {
+        self.0.get(c).copied()
+    }
+
+    /// 逐字转换，对照表中没有的字不变
+    pub fn convert(&self, s: &str) -> String {
+        s.chars().map(|c| self.get(&c).unwrap_or(c)).collect()
+    }
+
+    /// 合并另一对照表，同一字以后者为准
+    pub fn extend(&mut self, other: CharMap) {
+        self.0.extend(other.0);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn single_char(s: &str) -> Option<char> {
+    let mut chars = s.chars();
+    let c = chars.next()?;
+    chars.next().is_none().then_some(c)
+}
+
+/// 内置的繁体字→简体字对照表
+pub fn fanjian() -> &'static CharMap {
+    &FANJIAN
+}
+
+/// 内置的异体字→正字对照表
+pub fn yiti() -> &'static CharMap {
+    &YITI
+}
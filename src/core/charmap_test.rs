@@ -0,0 +1,34 @@
+use crate::core::charmap::{fanjian, yiti, CharMap};
+
+#[test]
+fn test_parse_char_map() {
+    let map = CharMap::parse("# 注释\n峯 峰\n\n牀\t床  # 行尾注释\n").expect("Should parse successfully");
+    assert_eq!(map.len(), 2);
+    assert_eq!(map.get(&'峯'), Some('峰'));
+    assert_eq!(map.get(&'牀'), Some('床'));
+    assert_eq!(map.get(&'峰'), None);
+}
+
+#[test]
+fn test_parse_char_map_error() {
+    let err = CharMap::parse("峯 峰\n峯峰\n").unwrap_err();
+    assert!(err.to_string().contains("第 2 行"));
+    assert!(CharMap::parse("峰 峰\n").is_err());
+}
+
+#[test]
+fn test_fanjian() {
+    assert_eq!(fanjian().get(&'東'), Some('东'));
+    assert_eq!(fanjian().get(&'樓'), Some('楼'));
+    assert_eq!(fanjian().get(&'东'), None);
+}
+
+#[test]
+fn test_yiti() {
+    assert_eq!(yiti().get(&'峯'), Some('峰'));
+    assert_eq!(yiti().get(&'鴈'), Some('雁'));
+    let mut map = CharMap::parse("峯 峰\n").unwrap();
+    map.extend(CharMap::parse("峯 蜂\n𡶶 峰\n").unwrap());
+    assert_eq!(map.get(&'峯'), Some('蜂'));
+    assert_eq!(map.len(), 2);
+}
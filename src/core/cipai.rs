@@ -1,26 +1,144 @@
 use std::fmt::{Display, Formatter};
+use colored::{Colorize, control::SHOULD_COLORIZE};
 use serde::{Deserialize, Serialize};
-use crate::core::tone::MeterTone;
-use crate::core::meter::{match_meter, MeterMatchResult};
+use crate::core::meter::{match_meter, match_stanza, stanza_name, Meter, MeterMatchResult};
+use crate::core::charmap::fanjian;
+use crate::core::description::CiPaiDescription;
+use crate::core::input::{parse_title, strip_title};
 use crate::core::rhyme::RhymeDict;
+use crate::core::tone::BasicTone;
+
+/// 词牌类别，按用韵分
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CiPaiCategory {
+    /// 平韵格
+    PingYun,
+    /// 仄韵格
+    ZeYun,
+    /// 平仄韵转换格
+    ZhuanHuan,
+    /// 平仄韵通叶格
+    TongYe,
+    /// 平仄韵错叶格
+    CuoYe,
+}
+
+impl CiPaiCategory {
+    pub const ALL: [CiPaiCategory; 5] = [
+        CiPaiCategory::PingYun,
+        CiPaiCategory::ZeYun,
+        CiPaiCategory::ZhuanHuan,
+        CiPaiCategory::TongYe,
+        CiPaiCategory::CuoYe,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CiPaiCategory::PingYun => "平韵格",
+            CiPaiCategory::ZeYun => "仄韵格",
+            CiPaiCategory::ZhuanHuan => "平仄韵转换格",
+            CiPaiCategory::TongYe => "平仄韵通叶格",
+            CiPaiCategory::CuoYe => "平仄韵错叶格",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CiPaiCategory> {
+        CiPaiCategory::ALL.into_iter().find(|c| c.name() == name)
+    }
+}
+
+impl Display for CiPaiCategory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
 
 // 词牌
 #[derive(Serialize, Deserialize)]
 pub struct CiPai {
     pub names: Vec<String>,
     pub variant: Option<String>,
-    pub description: Option<String>,
-    pub meter: Vec<Vec<MeterTone>>,
+    /// 类别，变体可单独指定，与所在类别不同
+    #[serde(default)]
+    pub category: Option<CiPaiCategory>,
+    pub description: Option<CiPaiDescription>,
+    /// 附注，针对本变体
+    #[serde(default)]
+    pub notes: Option<CiPaiDescription>,
+    /// 相关链接的文字及目标
+    #[serde(default)]
+    pub related_links: Vec<(String, String)>,
+    /// 说明中所述的字数
+    #[serde(default)]
+    pub char_count: Option<usize>,
+    /// 说明中所述的韵数，包括叠韵
+    #[serde(default)]
+    pub rhyme_count: Option<RhymeCounts>,
+    pub meter: Meter,
 }
 
 impl CiPai {
-    pub fn get_max_rhyme_num(&self) -> i32 {
-        self.meter
-            .iter()
-            .flat_map(|line| line.iter())
-            .filter_map(|tone| tone.rhyme_num)
-            .max()
-            .unwrap_or(0)
+    /// 是否与另一词牌为同一词牌：以本词牌的正名出现在对方名称中为准
+    pub fn is_same_cipai(&self, other: &CiPai) -> bool {
+        other.names.iter().any(|n| n == &self.names[0])
+    }
+
+    /// 正名或别名是否为该名称，繁体字按简体字比较
+    pub fn has_name(&self, name: &str) -> bool {
+        let name = fanjian().convert(name);
+        self.names.contains(&name)
+    }
+
+    /// 说明中所述的字数、韵数与格律不符之处。说明分写平韵、仄韵的，平韵、仄韵分别比较
+    pub fn count_mismatches(&self) -> Vec<String> {
+        let mut result = vec![];
+        if let Some(char_count) = self.char_count && char_count != self.meter.char_count() {
+            result.push(format!("说明为{}字，格律为{}字", char_count, self.meter.char_count()));
+        }
+        let Some(stated) = &self.rhyme_count else {
+            return result;
+        };
+        if stated.other > 0 {
+            if stated.total() != self.meter.rhyme_count() {
+                result.push(format!("说明为{}韵，格律为{}韵", stated.total(), self.meter.rhyme_count()));
+            }
+            return result;
+        }
+        for (count, tone) in [(stated.ping, BasicTone::Ping), (stated.ze, BasicTone::Ze)] {
+            let meter_count = self.meter.tone_rhyme_count(&tone);
+            if count == 0 && meter_count > 0 {
+                result.push(format!("说明未写{}韵，格律为{}{}韵", tone, meter_count, tone));
+            } else if count != meter_count {
+                result.push(format!("说明为{}{}韵，格律为{}{}韵", count, tone, meter_count, tone));
+            }
+        }
+        result
+    }
+
+    /// 用韵方式中韵位的数量
+    pub fn rhyme_slot_count(&self) -> usize {
+        self.meter.rhyme_scheme.slots.len()
+    }
+}
+
+/// 说明中所述的韵数，分平韵、仄韵及未写明平仄的韵（如“四韵”、“一叠韵”）
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RhymeCounts {
+    pub ping: usize,
+    pub ze: usize,
+    pub other: usize,
+}
+
+impl RhymeCounts {
+    pub fn total(&self) -> usize {
+        self.ping + self.ze + self.other
+    }
+
+    /// 所述平仄与格律的用韵是否相合：写了平韵的格律须有平韵，只写了平韵的格律不能有仄韵，仄韵亦然
+    pub fn fits(&self, meter: &Meter) -> bool {
+        let has = |tone: &BasicTone| meter.rhyme_scheme.slots.iter().any(|s| &s.tone == tone);
+        let fits = |count: usize, tone: BasicTone| if count > 0 { has(&tone) } else { self.other > 0 || !has(&tone) };
+        fits(self.ping, BasicTone::Ping) && fits(self.ze, BasicTone::Ze)
     }
 }
 
@@ -36,18 +154,74 @@ impl Display for CiPai {
             writeln!(f, "变体：{}", variant)?;
         }
 
+        if let Some(category) = &self.category {
+            writeln!(f, "类别：{}", category)?;
+        }
+
+        write!(f, "用韵：{}", self.meter.rhyme_scheme)?;
+        if self.meter.ru_rhyme {
+            write!(f, "；仄韵须用入声韵")?;
+        }
+        writeln!(f)?;
+
+        write!(f, "字数：{}，韵数：{}", self.meter.char_count(), self.meter.rhyme_count())?;
+        let mismatches = self.count_mismatches();
+        if !mismatches.is_empty() {
+            let mismatches = format!("（{}）", mismatches.join("，"));
+            if SHOULD_COLORIZE.should_colorize() {
+                write!(f, "{}", mismatches.red())?;
+            } else {
+                write!(f, "{}", mismatches)?;
+            }
+        }
+        writeln!(f)?;
+
         if let Some(description) = &self.description {
             writeln!(f, "说明：{}", description)?;
         }
 
         write!(f, "格律：")?;
-        for line in &self.meter {
+        let stanza_count = self.meter.stanza_starts.len();
+        for (i, line) in self.meter.lines.iter().enumerate() {
+            if let Some(stanza_i) = self.meter.stanza_starts.iter().position(|&s| s == i)
+                && let Some(name) = stanza_name(stanza_i, stanza_count) {
+                write!(f, "\n{}", name)?;
+            }
             write!(f, "\n--- ")?;
             for tone in line {
                 write!(f, "{}", tone)?;
             }
         }
 
+        if !self.meter.duiou.is_empty() {
+            let pairs: Vec<String> = self.meter.duiou
+                .iter()
+                .map(|&(a, b)| format!("第{}、{}句", self.meter.line_no(a), self.meter.line_no(b)))
+                .collect();
+            write!(f, "\n对偶：{}", pairs.join("；"))?;
+        }
+
+        if !self.meter.repeats.is_empty() {
+            let repeats: Vec<String> = self.meter.repeats
+                .iter()
+                .map(|r| format!("{}叠{}", self.meter.describe_positions(&r.target),
+                                 self.meter.describe_positions(&r.source)))
+                .collect();
+            write!(f, "\n叠句：{}", repeats.join("；"))?;
+        }
+
+        if let Some(notes) = &self.notes {
+            write!(f, "\n附注：{}", notes)?;
+        }
+
+        if !self.related_links.is_empty() {
+            let links: Vec<String> = self.related_links
+                .iter()
+                .map(|(text, target)| format!("{} → {}", text, target))
+                .collect();
+            write!(f, "\n相关链接：{}", links.join("；"))?;
+        }
+
         Ok(())
     }
 }
@@ -55,11 +229,17 @@ impl Display for CiPai {
 pub struct CiPaiMatchResult<'a> {
     pub cipai: &'a CiPai,
     pub match_result: MeterMatchResult,
+    /// 是否为文字标题中的词牌
+    pub title_match: bool,
 }
 
 impl Display for CiPaiMatchResult<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "词牌名：{}", self.cipai.names[0])?;
+        write!(f, "词牌名：{}", self.cipai.names[0])?;
+        if self.title_match {
+            write!(f, "（与标题相符）")?;
+        }
+        writeln!(f)?;
         if self.cipai.names.len() > 1 {
             writeln!(f, "别名：{}", self.cipai.names[1..].join("、"))?;
         }
@@ -67,40 +247,97 @@ impl Display for CiPaiMatchResult<'_> {
     }
 }
 
+/// Merge custom CiPai into the base list.
+///
+/// A custom entry with the same name and variant as an existing entry replaces it in place.
+/// A new variant of an existing CiPai is inserted after the last variant of that CiPai,
+/// and a new CiPai is appended at the end. Later entries override earlier ones.
+/// An entry of an existing CiPai keeps its names, followed by the new names in the custom entry.
+pub fn merge_cipai(mut base: Vec<CiPai>, extra: Vec<CiPai>) -> Vec<CiPai> {
+    for mut cipai in extra {
+        if let Some(i) = base.iter()
+            .position(|c| cipai.is_same_cipai(c) && c.variant == cipai.variant) {
+            cipai.names = merge_names(&base[i].names, cipai.names);
+            base[i] = cipai;
+        } else if let Some(i) = base.iter().rposition(|c| cipai.is_same_cipai(c)) {
+            cipai.names = merge_names(&base[i].names, cipai.names);
+            base.insert(i + 1, cipai);
+        } else {
+            base.push(cipai);
+        }
+    }
+    base
+}
+
+/// 已有的名称在前，再加上其中没有的新名称
+fn merge_names(names: &[String], extra: Vec<String>) -> Vec<String> {
+    let mut merged = names.to_vec();
+    for name in extra {
+        if !merged.contains(&name) {
+            merged.push(name);
+        }
+    }
+    merged
+}
+
 /// Find the best matching CiPai from a list of candidates
 ///
 /// Takes a vector of CiPai, a rhyme dictionary, and input text.
 /// Returns a vector of CiPaiMatchResult sorted by score in descending order.
+/// If the text starts with a title line naming a CiPai, its variants are ranked first.
 pub fn best_match<'a>(
     cipais: &'a [CiPai],
     rhyme_dict: &RhymeDict,
     input_text: &str,
 ) -> Vec<CiPaiMatchResult<'a>> {
+    let title = parse_title(input_text);
+    let input_text = strip_title(input_text, |name| cipais.iter().any(|c| c.has_name(name)));
     let mut results: Vec<CiPaiMatchResult> = cipais
         .iter()
         .map(|cipai| {
-            // Convert Vec<Vec<MeterTone>> to Vec<Arc<[MeterTone]>>
-            let meter: Vec<std::sync::Arc<[MeterTone]>> = cipai
-                .meter
-                .iter()
-                .map(|line| std::sync::Arc::from(line.as_slice()))
-                .collect();
-
-            let match_result = match_meter(rhyme_dict, input_text, &meter, true);
+            let match_result = match_meter(rhyme_dict, input_text, &cipai.meter, true);
 
             CiPaiMatchResult {
                 cipai,
                 match_result,
+                title_match: title.as_ref().is_some_and(|t| cipai.has_name(&t.name)),
             }
         })
         .collect();
 
     results.sort_by(|a, b| {
-        a.match_result
+        b.title_match.cmp(&a.title_match).then_with(|| a.match_result
             .partial_cmp(&b.match_result)
-            .unwrap_or(std::cmp::Ordering::Equal)
+            .unwrap_or(std::cmp::Ordering::Equal))
     });
 
     results
 }
 
+
+/// 将文字与同一词牌的各个变体匹配，按匹配结果从好到差排序。
+/// 只填了一片时，每个变体取与文字最相合的一片。文字开头的标题行须先用 strip_title 去掉。
+pub fn rank_variants<'a>(
+    variants: &[&'a CiPai],
+    rhyme_dict: &RhymeDict,
+    input_text: &str,
+    single_stanza: bool,
+) -> Vec<CiPaiMatchResult<'a>> {
+    let mut results: Vec<CiPaiMatchResult> = variants
+        .iter()
+        .map(|&cipai| {
+            let match_result = if single_stanza {
+                match_stanza(rhyme_dict, input_text, &cipai.meter).remove(0).1
+            } else {
+                match_meter(rhyme_dict, input_text, &cipai.meter, false)
+            };
+            CiPaiMatchResult {
+                cipai,
+                match_result,
+                title_match: false,
+            }
+        })
+        .collect();
+    results.sort_by(|a, b| a.match_result.cmp(&b.match_result));
+    results
+}
@@ -0,0 +1,176 @@
+use std::fmt::{Display, Formatter};
+use colored::{Colorize, control::SHOULD_COLORIZE};
+use serde::{Deserialize, Serialize};
+
+/// 说明、附注中的一段带标记的文字
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DescriptionSpan {
+    Text(String),
+    /// 专名，如人名、地名、朝代
+    Name(String),
+    /// 引用
+    Quote(Vec<DescriptionSpan>),
+    /// 着重
+    Emphasis(Vec<DescriptionSpan>),
+    /// 注释
+    Note(Vec<DescriptionSpan>),
+    /// 链接。target 为原始的 xhref：“@作者”指向作者，“$编号”指向例词，“#人名”指向人物，
+    /// “@”、“#”后为空时即以链接文字为名
+    Link { target: String, content: Vec<DescriptionSpan> },
+}
+
+impl DescriptionSpan {
+    /// 去掉标记后的文字
+    pub fn text(&self) -> String {
+        match self {
+            DescriptionSpan::Text(t) | DescriptionSpan::Name(t) => t.clone(),
+            DescriptionSpan::Quote(c) | DescriptionSpan::Emphasis(c) | DescriptionSpan::Note(c)
+            | DescriptionSpan::Link { content: c, .. } => spans_text(c),
+        }
+    }
+
+    /// 链接所指的目标，“@”、“#”后为空时补上链接文字
+    pub fn link_target(&self) -> Option<String> {
+        match self {
+            DescriptionSpan::Link { target, .. } if target == "@" || target == "#" =>
+                Some(format!("{}{}", target, self.text())),
+            DescriptionSpan::Link { target, .. } => Some(target.clone()),
+            _ => None,
+        }
+    }
+
+    fn children(&self) -> &[DescriptionSpan] {
+        match self {
+            DescriptionSpan::Text(_) | DescriptionSpan::Name(_) => &[],
+            DescriptionSpan::Quote(c) | DescriptionSpan::Emphasis(c) | DescriptionSpan::Note(c)
+            | DescriptionSpan::Link { content: c, .. } => c,
+        }
+    }
+}
+
+fn spans_text(spans: &[DescriptionSpan]) -> String {
+    spans.iter().map(|s| s.text()).collect()
+}
+
+/// 词牌的说明或附注，由若干段落组成
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CiPaiDescription {
+    pub paragraphs: Vec<Vec<DescriptionSpan>>,
+}
+
+impl CiPaiDescription {
+    /// 去掉标记后的全文，段落之间换行
+    pub fn text(&self) -> String {
+        self.paragraphs.iter().map(|p| spans_text(p)).collect::<Vec<_>>().join("\n")
+    }
+
+    /// 按先后顺序遍历所有片段，包括嵌套在引用、注释等之中的片段
+    pub fn spans(&self) -> Vec<&DescriptionSpan> {
+        fn walk<'a>(spans: &'a [DescriptionSpan], result: &mut Vec<&'a DescriptionSpan>) {
+            for span in spans {
+                result.push(span);
+                walk(span.children(), result);
+            }
+        }
+        let mut result = vec![];
+        for p in &self.paragraphs {
+            walk(p, &mut result);
+        }
+        result
+    }
+
+    /// 所有专名，去重
+    pub fn names(&self) -> Vec<String> {
+        let mut names = vec![];
+        for span in self.spans() {
+            if let DescriptionSpan::Name(name) = span && !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+
+    pub fn quotes(&self) -> Vec<String> {
+        self.collect_text(|s| matches!(s, DescriptionSpan::Quote(_)))
+    }
+
+    pub fn emphases(&self) -> Vec<String> {
+        self.collect_text(|s| matches!(s, DescriptionSpan::Emphasis(_)))
+    }
+
+    pub fn notes(&self) -> Vec<String> {
+        self.collect_text(|s| matches!(s, DescriptionSpan::Note(_)))
+    }
+
+    /// 所有链接的文字及目标
+    pub fn links(&self) -> Vec<(String, String)> {
+        self.spans().into_iter()
+            .filter_map(|s| Some((s.text(), s.link_target()?)))
+            .collect()
+    }
+
+    fn collect_text(&self, filter: impl Fn(&DescriptionSpan) -> bool) -> Vec<String> {
+        self.spans().into_iter().filter(|s| filter(s)).map(|s| s.text()).collect()
+    }
+}
+
+/// 渲染片段：引用、着重、专名以不同样式显示，注释以“［注n］”标出，链接文字后标“［链n］”。
+/// 注释和链接的内容依次收集到 notes 和 links 中
+fn render_spans(spans: &[DescriptionSpan], notes: &mut Vec<String>, links: &mut Vec<(String, String)>)
+        -> String {
+    let colorize = SHOULD_COLORIZE.should_colorize();
+    let mut result = String::new();
+    for span in spans {
+        match span {
+            DescriptionSpan::Text(t) => result.push_str(t),
+            DescriptionSpan::Name(t) if colorize => result.push_str(&t.underline().to_string()),
+            DescriptionSpan::Name(t) => result.push_str(t),
+            DescriptionSpan::Quote(c) => {
+                let quote = render_spans(c, notes, links);
+                if colorize {
+                    result.push_str(&quote.italic().cyan().to_string());
+                } else {
+                    result.push_str(&quote);
+                }
+            }
+            DescriptionSpan::Emphasis(c) => {
+                let emphasis = render_spans(c, notes, links);
+                if colorize {
+                    result.push_str(&emphasis.bold().to_string());
+                } else {
+                    result.push_str(&emphasis);
+                }
+            }
+            DescriptionSpan::Note(_) => {
+                notes.push(span.text());
+                result.push_str(&format!("［注{}］", notes.len()));
+            }
+            DescriptionSpan::Link { content, .. } => {
+                result.push_str(&render_spans(content, notes, links));
+                links.push((span.text(), span.link_target().unwrap_or_default()));
+                result.push_str(&format!("［链{}］", links.len()));
+            }
+        }
+    }
+    result
+}
+
+impl Display for CiPaiDescription {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut notes = vec![];
+        let mut links = vec![];
+        for (i, p) in self.paragraphs.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", render_spans(p, &mut notes, &mut links))?;
+        }
+        for (i, note) in notes.iter().enumerate() {
+            write!(f, "\n　注{}：{}", i + 1, note)?;
+        }
+        for (i, (text, target)) in links.iter().enumerate() {
+            write!(f, "\n　链{}：{} → {}", i + 1, text, target)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::Arc;
+
+/// 输入文字中字符的类别
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CharClass {
+    /// 汉字，参与格律匹配
+    Text,
+    /// 分句，如句号、逗号、分号、冒号、省略号、破折号、空白和换行
+    Break,
+    /// 句中停顿，如顿号标出的豆，不分句
+    Pause,
+    /// 忽略，如引号、书名号、括号、零宽字符、行号、拉丁字母
+    Ignorable,
+}
+
+const BREAK_CHARS: [char; 18] = [
+    '。', '，', '？', '！', '；', '：', '…', '—', '―', '｡', '．',
+    '.', ',', '?', '!', ';', ':', '～',
+];
+const PAUSE_CHARS: [char; 5] = ['、', '､', '·', '・', '‧'];
+
+pub fn classify_char(c: char) -> CharClass {
+    if is_han(c) {
+        CharClass::Text
+    } else if c.is_whitespace() || BREAK_CHARS.contains(&c) {
+        CharClass::Break
+    } else if PAUSE_CHARS.contains(&c) {
+        CharClass::Pause
+    } else {
+        CharClass::Ignorable
+    }
+}
+
+/// 汉字，含扩展区、兼容汉字及〇、々
+fn is_han(c: char) -> bool {
+    matches!(c,
+        '\u{3005}' | '\u{3007}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{20000}'..='\u{2FA1F}'
+        | '\u{30000}'..='\u{323AF}')
+}
+
+/// 标题中词牌名与题目之间的间隔号
+const TITLE_DOTS: [char; 4] = ['·', '・', '‧', '•'];
+/// 词牌名的最大字数
+const MAX_NAME_LEN: usize = 8;
+
+/// 文字开头的标题行，如“定风波·多伦多初冬”、“定风波（多伦多初冬）”、“《定风波》”
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Title {
+    /// 词牌名
+    pub name: String,
+    /// 题目，没有时为 None
+    pub subtitle: Option<String>,
+    /// 词牌名用书名号标出。间隔号也用作豆、括号也用于注释，只有书名号不会与正文混淆
+    pub quoted: bool,
+    /// 标题行（含换行符）在原文中结束的字节偏移
+    pub end: usize,
+}
+
+/// 识别文字第一个非空行是否为标题。标题后须另有正文，词牌名须为汉字
+pub fn parse_title(input: &str) -> Option<Title> {
+    let start = input.len() - input.trim_start().len();
+    // 标题须单独成行
+    let end = start + input[start..].find('\n')? + 1;
+    let line = input[start..end].trim();
+    let quoted = line.starts_with('《');
+    let (name, subtitle) = if let Some(rest) = line.strip_prefix('《') {
+        let (inner, after) = rest.split_once('》')?;
+        match inner.split_once(TITLE_DOTS) {
+            Some((name, subtitle)) => (name, subtitle),
+            None => (inner, after),
+        }
+    } else if let Some((name, subtitle)) = line.split_once(TITLE_DOTS) {
+        (name, subtitle)
+    } else {
+        let inner = line.strip_suffix('）').or_else(|| line.strip_suffix(')'))?;
+        inner.split_once(['（', '('])?
+    };
+    let name = name.trim();
+    let len = name.chars().count();
+    if !(2..=MAX_NAME_LEN).contains(&len) || name.chars().any(|c| classify_char(c) != CharClass::Text) {
+        return None;
+    }
+    let subtitle = subtitle.trim();
+    Some(Title {
+        name: name.to_string(),
+        subtitle: (!subtitle.is_empty()).then(|| subtitle.to_string()),
+        quoted,
+        end,
+    })
+}
+
+/// 规范化后的一句
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputLine {
+    /// 只含汉字
+    pub text: Arc<String>,
+    /// 每个字在原文中的字节偏移
+    pub offsets: Arc<[usize]>,
+}
+
+/// 去掉开头的标题行。用间隔号、括号的标题须是已知的词牌名（由 is_name 判断），以免误去正文的第一句
+/// 返回原文的后缀，之后得到的字节偏移都相对于它
+pub fn strip_title(input: &str, is_name: impl Fn(&str) -> bool) -> &str {
+    match parse_title(input) {
+        Some(title) if title.quoted || is_name(&title.name) => &input[title.end..],
+        _ => input,
+    }
+}
+
+/// 将输入文字按分句符号切分为句，只保留汉字，并记录每个字在原文中的位置。
+/// 开头用书名号标出的标题行不计入，其他形式的标题须先用 strip_title 去掉
+pub fn normalize_input(input: &str) -> Vec<InputLine> {
+    let start = parse_title(input).filter(|t| t.quoted).map_or(0, |t| t.end);
+    let mut lines = vec![];
+    let mut text = String::new();
+    let mut offsets = vec![];
+    for (offset, c) in input[start..].char_indices() {
+        let offset = start + offset;
+        match classify_char(c) {
+            CharClass::Text => {
+                text.push(c);
+                offsets.push(offset);
+            }
+            CharClass::Break if !text.is_empty() => {
+                lines.push(InputLine { text: Arc::new(std::mem::take(&mut text)), offsets: offsets.drain(..).collect() });
+            }
+            _ => {}
+        }
+    }
+    if !text.is_empty() {
+        lines.push(InputLine { text: Arc::new(text), offsets: offsets.into() });
+    }
+    lines
+}
@@ -0,0 +1,73 @@
+use crate::core::input::{classify_char, normalize_input, parse_title, strip_title, CharClass};
+
+#[test]
+fn test_classify_char() {
+    assert_eq!(classify_char('东'), CharClass::Text);
+    assert_eq!(classify_char('𩧢'), CharClass::Text);
+    for c in ['，', '。', '；', '：', '…', '—', '\n', '\u{3000}', ' ', '!'] {
+        assert_eq!(classify_char(c), CharClass::Break, "{:?}", c);
+    }
+    assert_eq!(classify_char('、'), CharClass::Pause);
+    for c in ['“', '”', '《', '》', '（', '）', '\u{200B}', '\u{FEFF}', '1', 'a', 'Ａ'] {
+        assert_eq!(classify_char(c), CharClass::Ignorable, "{:?}", c);
+    }
+}
+
+#[test]
+fn test_normalize_input() {
+    let input = "1. 明月别枝\u{200B}惊鹊；清风半夜鸣蝉……\n“稻花香里”说丰年";
+    let lines = normalize_input(input);
+    let texts: Vec<&str> = lines.iter().map(|l| l.text.as_str()).collect();
+    assert_eq!(texts, vec!["明月别枝惊鹊", "清风半夜鸣蝉", "稻花香里说丰年"]);
+    for line in &lines {
+        for (c, &offset) in line.text.chars().zip(line.offsets.iter()) {
+            assert!(input[offset..].starts_with(c));
+        }
+    }
+    // 豆不分句
+    let lines = normalize_input("渐霜风、凄紧");
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].text.as_str(), "渐霜风凄紧");
+    assert_eq!(lines[0].offsets[3], "渐霜风、".len());
+    assert!(normalize_input(" \u{3000}，abc").is_empty());
+}
+
+#[test]
+fn test_parse_title() {
+    let input = "定风波·多伦多初冬\n\n枫叶飘零雪欲来，湖光渐冷起风埃。";
+    let title = parse_title(input).unwrap();
+    assert_eq!(title.name, "定风波");
+    assert_eq!(title.subtitle.as_deref(), Some("多伦多初冬"));
+    assert!(!title.quoted);
+    // 已知的词牌名才去掉
+    let text = strip_title(input, |name| name == "定风波");
+    assert!(text.starts_with("\n枫叶"));
+    assert_eq!(normalize_input(text)[0].text.as_str(), "枫叶飘零雪欲来");
+    assert_eq!(normalize_input(input)[0].text.as_str(), "定风波多伦多初冬");
+
+    let title = parse_title("\n 定风波（多伦多初冬）\n枫叶飘零").unwrap();
+    assert_eq!((title.name.as_str(), title.subtitle.as_deref()), ("定风波", Some("多伦多初冬")));
+    let input = "《定风波》\n枫叶飘零";
+    let title = parse_title(input).unwrap();
+    assert_eq!((title.name.as_str(), title.subtitle), ("定风波", None));
+    // 书名号标出的标题不论词牌名是否已知都去掉
+    assert!(title.quoted);
+    assert_eq!(strip_title(input, |_| false), "枫叶飘零");
+    let lines = normalize_input(input);
+    assert_eq!(lines[0].text.as_str(), "枫叶飘零");
+    assert!(input[lines[0].offsets[0]..].starts_with('枫'));
+    let title = parse_title("《水调歌头·明月几时有》\n明月几时有").unwrap();
+    assert_eq!(title.name, "水调歌头");
+
+    // 不是标题
+    assert_eq!(parse_title("枫叶飘零雪欲来，湖光渐冷起风埃。\n塔影朦胧霜叶败"), None);
+    assert_eq!(parse_title("定风波·多伦多初冬"), None);
+    assert_eq!(parse_title("1·多伦多\n枫叶飘零"), None);
+
+    // 短短的首句用间隔号作豆，形如标题，但不是词牌名
+    let input = "渐霜风·凄紧\n关河冷落，残照当楼。";
+    assert_eq!(parse_title(input).map(|t| t.name), Some("渐霜风".to_string()));
+    assert_eq!(strip_title(input, |name| name == "八声甘州"), input);
+    let texts: Vec<String> = normalize_input(input).into_iter().map(|l| l.text.as_str().to_string()).collect();
+    assert_eq!(texts, vec!["渐霜风凄紧", "关河冷落", "残照当楼"]);
+}
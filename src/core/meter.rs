@@ -1,32 +1,177 @@
-use crate::core::rhyme::{Rhyme, RhymeDict};
-use crate::core::tone::{tone_match, BasicTone, MeterTone, MeterToneType};
+use crate::core::input::normalize_input;
+use crate::core::rhyme::{Rhyme, RhymeDict, RhymeId};
+use crate::core::scheme::{slot_name, RhymeScheme};
+use crate::core::tone::{tone_match, BasicTone, MeterTone, MeterToneKind, MeterToneType, ShengDiao};
 use colored::control::SHOULD_COLORIZE;
 use colored::Colorize;
+use serde::{Deserialize, Serialize};
 use std::cmp::{max, Ordering};
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 use std::sync::Arc;
 
+/// 韵脚分配：下标为韵位，值为该韵位所押的韵，None 为不押韵
+type RhymeAssignment = Vec<Option<Arc<Rhyme>>>;
+
+/// 每个字匹配时所取的读音，韵书未收录或多出的字为 None
+type Readings = Vec<Option<Arc<Rhyme>>>;
+
+/// 一句的匹配结果：分数、每个字的匹配类型、格律每个位置对应的字、每个字所取的读音
+type SentenceMatch = (f64, Vec<MatchType>, Vec<Option<usize>>, Readings);
+
+/// 多种韵脚分配共用的一句匹配结果
+type SharedSentenceMatch = (f64, Arc<Vec<MatchType>>, Arc<Vec<Option<usize>>>, Arc<Readings>);
+
+/// 词牌格律
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Meter {
+    /// 每句的格律
+    pub lines: Vec<Vec<MeterTone>>,
+    /// 对偶句，每项为两句在 lines 中的下标
+    pub duiou: Vec<(usize, usize)>,
+    /// 叠句、叠韵
+    pub repeats: Vec<Repeat>,
+    /// 每片第一句在 lines 中的下标
+    pub stanza_starts: Vec<usize>,
+    /// 用韵方式，韵脚的 rhyme 为其中韵位的下标
+    #[serde(default)]
+    pub rhyme_scheme: RhymeScheme,
+    /// 仄韵须用入声韵
+    #[serde(default)]
+    pub ru_rhyme: bool,
+}
+
+/// 叠句或叠韵：target 中的字须与 source 中对应位置的字相同。
+/// 每个位置为 (句在 lines 中的下标, 字在句中的下标)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Repeat {
+    pub source: Vec<(usize, usize)>,
+    pub target: Vec<(usize, usize)>,
+}
+
+impl Meter {
+    /// 字数，不计可省略的字
+    pub fn char_count(&self) -> usize {
+        self.lines.iter().flatten().filter(|t| !t.optional).count()
+    }
+
+    /// 韵数，不计可省略的字
+    pub fn rhyme_count(&self) -> usize {
+        self.lines.iter().flatten().filter(|t| !t.optional && t.rhyme.is_some()).count()
+    }
+
+    /// 平韵或仄韵的韵数，不计可省略的字
+    pub fn tone_rhyme_count(&self, tone: &BasicTone) -> usize {
+        self.lines.iter().flatten()
+            .filter(|t| !t.optional && t.rhyme.is_some_and(|slot| &self.rhyme_scheme.slots[slot].tone == tone))
+            .count()
+    }
+
+    /// 句子的序号，从 1 开始
+    pub fn line_no(&self, idx: usize) -> usize {
+        idx + 1
+    }
+
+    /// 每片包含的句子在 lines 中的下标范围
+    pub fn stanzas(&self) -> Vec<Range<usize>> {
+        let mut ends: Vec<usize> = self.stanza_starts.iter().skip(1).cloned().collect();
+        ends.push(self.lines.len());
+        self.stanza_starts.iter().zip(ends).map(|(&start, end)| start..end).collect()
+    }
+
+    /// 句子所在片的序号，从 0 开始
+    pub fn stanza_of(&self, line_idx: usize) -> usize {
+        self.stanza_starts.iter().rposition(|&s| s <= line_idx).unwrap_or(0)
+    }
+
+    /// 取出其中一片作为单独的格律
+    pub fn stanza(&self, i: usize) -> Meter {
+        let range = self.stanzas()[i].clone();
+        let in_range = |line_idx: &usize| range.contains(line_idx);
+        let shift = |(line_idx, pos): &(usize, usize)| (line_idx - range.start, *pos);
+        Meter {
+            lines: self.lines[range.clone()].to_vec(),
+            duiou: self.duiou.iter()
+                .filter(|(a, b)| in_range(a) && in_range(b))
+                .map(|(a, b)| (a - range.start, b - range.start))
+                .collect(),
+            repeats: self.repeats.iter()
+                .filter(|r| r.source.iter().chain(r.target.iter()).all(|(l, _)| in_range(l)))
+                .map(|r| Repeat {
+                    source: r.source.iter().map(shift).collect(),
+                    target: r.target.iter().map(shift).collect(),
+                })
+                .collect(),
+            stanza_starts: vec![0],
+            rhyme_scheme: self.rhyme_scheme.clone(),
+            ru_rhyme: self.ru_rhyme,
+        }
+    }
+
+    /// 描述一组连续位置，如“第3句”、“第2句第3至4字”
+    pub fn describe_positions(&self, positions: &[(usize, usize)]) -> String {
+        let mut parts = vec![];
+        let mut i = 0;
+        while i < positions.len() {
+            let line_idx = positions[i].0;
+            let mut j = i;
+            while j + 1 < positions.len() && positions[j + 1].0 == line_idx {
+                j += 1;
+            }
+            let line_no = self.line_no(line_idx);
+            let (from, to) = (positions[i].1, positions[j].1);
+            if from == 0 && to + 1 == self.lines[line_idx].len() {
+                parts.push(format!("第{}句", line_no));
+            } else if from == to {
+                parts.push(format!("第{}句第{}字", line_no, from + 1));
+            } else {
+                parts.push(format!("第{}句第{}至{}字", line_no, from + 1, to + 1));
+            }
+            i = j + 1;
+        }
+        parts.join("、")
+    }
+}
+
+/// 片的名称：两片为上片、下片，多于两片为第一段、第二段等，单片无名称
+pub fn stanza_name(i: usize, count: usize) -> Option<String> {
+    const NUMS: [&str; 9] = ["一", "二", "三", "四", "五", "六", "七", "八", "九"];
+    match count {
+        0 | 1 => None,
+        2 => Some(if i == 0 { "上片" } else { "下片" }.to_string()),
+        _ => Some(format!("第{}段", NUMS.get(i).map(|n| n.to_string()).unwrap_or((i + 1).to_string()))),
+    }
+}
+
 /// 获取匹配结果颜色说明
 pub fn get_match_legend() -> String {
     if SHOULD_COLORIZE.should_colorize() {
         format!(
-            "匹配结果说明：{}=完全匹配 {}=仅音调匹配 {}=不匹配",
+            "匹配结果说明：{}=完全匹配 {}=仅音调匹配 {}=不匹配 {}=未按叠句重复 {}=仄声但非去声 {}=韵书未收录 {}=多音字，取相合的读音 {}=异体字，按正字查韵书",
             "字(默认颜色)".normal(),
             "字(橙色)".truecolor(255, 165, 0),
-            "字(红色)".red()
+            "字(红色)".red(),
+            "字(紫色)".magenta(),
+            "字(蓝色)".blue(),
+            "字(灰色下划线)".bright_black().underline(),
+            "字(绿色)".green(),
+            "字(斜体)".italic()
         )
     } else {
-        "匹配结果说明：字后括号内说明匹配错误原因：平仄错或是韵脚错".to_string()
+        "匹配结果说明：字后括号内说明匹配错误原因：平仄错、韵脚错、叠字错或非去声，未收录表示韵书中没有此字，多音字表示有平仄两读、取与格律相合的读音，→后为异体字查韵书所用的正字".to_string()
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MatchType {
     NoMatch,
     ToneOnly,
     AllMatch,
+    RepeatMismatch, // 叠句、叠韵处没有重复前面的字
+    NotQu, // 格律要求去声，所填字是仄声但不是去声
+    Ambiguous, // 多音字，首选读音不合格律，取另一读音才匹配
+    Unknown, // 韵书未收录此字，无法判断
 }
 
 
@@ -34,12 +179,50 @@ pub struct SentenceMatchResult {
     pub match_result: Option<Arc<Vec<MatchType>>>, // is None if either text or meter is None
     pub text: Option<Arc<String>>, // None if there is no meter between the text
     pub meter: Option<Arc<[MeterTone]>>,
+    /// 格律中每个位置对应的字在 text 中的下标，省略的位置为 None。text 或 meter 为 None 时为 None
+    pub tone_chars: Option<Arc<Vec<Option<usize>>>>,
+    /// 本句的匹配分数，未归一化。text 或 meter 为 None 时为 0
+    pub score: f64,
+    /// 每个字匹配时所取的读音，与 match_result 一一对应
+    pub readings: Option<Arc<Readings>>,
+    /// 每个字在输入原文中的字节偏移，与 text 中的字一一对应
+    pub offsets: Option<Arc<[usize]>>,
+    /// 异体字折合后查韵书所用的字形，与 text 中的字一一对应，未折合的字为 None。没有折合的字时为 None
+    pub folded: Option<Arc<Vec<Option<char>>>>,
+}
+
+impl SentenceMatchResult {
+    /// 第 i 个字匹配时所取的读音
+    pub fn reading_at(&self, i: usize) -> Option<&Arc<Rhyme>> {
+        self.readings.as_ref()?.get(i)?.as_ref()
+    }
+
+    /// 格律第 i 个位置对应的字
+    pub fn char_at(&self, i: usize) -> Option<char> {
+        let char_idx = (*self.tone_chars.as_ref()?.get(i)?)?;
+        self.text.as_ref()?.chars().nth(char_idx)
+    }
+
+    /// 第 i 个字按异体字折合后的字形
+    pub fn folded_at(&self, i: usize) -> Option<char> {
+        *self.folded.as_ref()?.get(i)?
+    }
 }
 
 impl Display for SentenceMatchResult {
+    #[allow(clippy::unnecessary_unwrap)]
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         if self.text.is_some() {
             write!(f, "+++ ")?;
+            // 在对应格律中有豆的字后显示豆
+            let dou_chars: Vec<usize> = match (&self.meter, &self.tone_chars) {
+                (Some(meter), Some(tone_chars)) => meter.iter()
+                    .zip(tone_chars.iter())
+                    .filter(|(t, _)| t.dou)
+                    .filter_map(|(_, c)| *c)
+                    .collect(),
+                _ => vec![],
+            };
             for (i, char) in self.text.as_ref().unwrap().chars().enumerate() {
                 let char_str = char.to_string();
                 if SHOULD_COLORIZE.should_colorize() {
@@ -51,10 +234,20 @@ impl Display for SentenceMatchResult {
                             MatchType::ToneOnly =>
                                 char_str.truecolor(255, 165, 0), // orange
                             MatchType::AllMatch => char_str.normal(),
+                            MatchType::RepeatMismatch => char_str.magenta(),
+                            MatchType::NotQu => char_str.blue(),
+                            MatchType::Unknown => char_str.bright_black().underline(),
+                            MatchType::Ambiguous => char_str.green(),
                         }
                     };
+                    // 异体字以斜体标出
+                    let colored_char = if self.folded_at(i).is_some() { colored_char.italic() } else { colored_char };
                     write!(f, "{}", colored_char)?;
                 } else {
+                    let char_str = match self.folded_at(i) {
+                        Some(folded) => format!("{}（→{}）", char_str, folded),
+                        None => char_str,
+                    };
                     let anno_char = if self.match_result.is_none() || self.match_result.as_ref().unwrap().is_empty() {
                         char_str
                     } else {
@@ -62,17 +255,35 @@ impl Display for SentenceMatchResult {
                             MatchType::NoMatch => format!("{}（平仄错）", char_str),
                             MatchType::ToneOnly => format!("{}（韵脚错）", char_str),
                             MatchType::AllMatch => char_str,
+                            MatchType::RepeatMismatch => format!("{}（叠字错）", char_str),
+                            MatchType::NotQu => format!("{}（非去声）", char_str),
+                            MatchType::Unknown => format!("{}（未收录）", char_str),
+                            MatchType::Ambiguous => match self.reading_at(i) {
+                                Some(reading) => format!("{}（多音字，取{}）", char_str, reading.sheng_diao),
+                                None => format!("{}（多音字）", char_str),
+                            },
                         }
                     };
                     write!(f, "{}", anno_char)?;
                 }
+                if dou_chars.contains(&i) {
+                    write!(f, "、")?;
+                }
             }
             writeln!(f)?;
         }
         if self.meter.is_some() {
             write!(f, "--- ")?;
-            for tone in self.meter.as_ref().unwrap().as_ref() {
-                write!(f, "{}", tone)?;
+            for (i, tone) in self.meter.as_ref().unwrap().iter().enumerate() {
+                let omitted = self.tone_chars.as_ref().is_some_and(|c| c[i].is_none());
+                if tone.optional && omitted {
+                    write!(f, "〔省〕")?;
+                    if tone.dou {
+                        write!(f, "、")?;
+                    }
+                } else {
+                    write!(f, "{}", tone)?;
+                }
             }
             writeln!(f)?;
         }
@@ -81,12 +292,201 @@ impl Display for SentenceMatchResult {
 }
 
 
+/// 对偶句的对仗检查结果
+pub struct DuiouMatchResult {
+    pub text: (Arc<String>, Arc<String>),
+    pub length_match: bool,
+    /// 格律要求平仄相对，但所填字平仄相同的位置，从 1 开始，不计领格字
+    pub tone_mismatch: Vec<usize>,
+    /// 两句中重复出现的字
+    pub repeated_chars: Vec<char>,
+}
+
+impl DuiouMatchResult {
+    pub fn is_match(&self) -> bool {
+        self.length_match && self.tone_mismatch.is_empty() && self.repeated_chars.is_empty()
+    }
+}
+
+impl Display for DuiouMatchResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} / {}：", self.text.0, self.text.1)?;
+        if self.is_match() {
+            return write!(f, "工整");
+        }
+        let mut problems = vec![];
+        if !self.length_match {
+            problems.push("字数不同".to_string());
+        }
+        if !self.tone_mismatch.is_empty() {
+            let positions: Vec<String> = self.tone_mismatch.iter().map(|p| p.to_string()).collect();
+            problems.push(format!("第{}字平仄未相对", positions.join("、")));
+        }
+        if !self.repeated_chars.is_empty() {
+            let chars: Vec<String> = self.repeated_chars.iter().map(|c| c.to_string()).collect();
+            problems.push(format!("重字：{}", chars.join("、")));
+        }
+        let problems = problems.join("，");
+        if SHOULD_COLORIZE.should_colorize() {
+            write!(f, "{}", problems.red())
+        } else {
+            write!(f, "{}", problems)
+        }
+    }
+}
+
+/// 匹配时推定的韵脚：格律中出现的各韵位所押的韵，None 为不押韵
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChosenRhymes(pub Vec<(usize, Option<Arc<Rhyme>>)>);
+
+impl ChosenRhymes {
+    fn new(slots: &[usize], assignment: &RhymeAssignment) -> ChosenRhymes {
+        ChosenRhymes(slots.iter().map(|&slot| (slot, assignment[slot].clone())).collect())
+    }
+}
+
+/// 所押的韵，如“第一部 平声”，None 为“未押韵”
+fn rhyme_label(rhyme: &Option<Arc<Rhyme>>) -> String {
+    match rhyme {
+        Some(rhyme) => format!("{} {}", rhyme.group.as_ref().unwrap_or(&rhyme.name), rhyme.sheng_diao),
+        None => "未押韵".to_string(),
+    }
+}
+
+impl Display for ChosenRhymes {
+    /// 如“韵0 → 第一部 平声，韵1 → 未押韵”
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let rhymes: Vec<String> = self.0.iter()
+            .map(|(slot, rhyme)| format!("{} → {}", slot_name(*slot), rhyme_label(rhyme)))
+            .collect();
+        write!(f, "{}", rhymes.join("，"))
+    }
+}
+
+/// 须同部的两个韵位所押的韵不同部，如通叶的平韵与仄韵
+pub struct GroupMismatch {
+    pub slots: (usize, usize),
+    pub rhymes: (Arc<Rhyme>, Arc<Rhyme>),
+    /// 须同部的缘由，如“通叶”、“错叶”
+    pub link: &'static str,
+}
+
+impl Display for GroupMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let describe = |slot: usize, rhyme: &Rhyme| format!("{}押{}{}声", slot_name(slot),
+            rhyme.group.as_ref().unwrap_or(&rhyme.name), rhyme.tone);
+        let message = format!("{}不同部：{}，{}", self.link, describe(self.slots.0, &self.rhymes.0),
+                              describe(self.slots.1, &self.rhymes.1));
+        if SHOULD_COLORIZE.should_colorize() {
+            write!(f, "{}", message.red())
+        } else {
+            write!(f, "{}", message)
+        }
+    }
+}
+
+/// 一个韵段的用韵情况
+pub struct SegmentMatch {
+    pub segment: usize,
+    /// 韵段中各韵位所押的韵，未押韵时为 None
+    pub rhymes: Vec<(usize, Option<Arc<Rhyme>>)>,
+    /// 韵段中不押所分配的韵的韵脚字
+    pub off_rhyme: Vec<char>,
+    /// 与格律中相接的上一韵段用同一韵部，而格律要求换部
+    pub same_as_previous: bool,
+}
+
+impl SegmentMatch {
+    pub fn is_match(&self) -> bool {
+        self.rhymes.iter().all(|(_, r)| r.is_some()) && self.off_rhyme.is_empty() && !self.same_as_previous
+    }
+
+    /// 韵段所用的韵部。韵书不分韵部时为韵名
+    pub fn groups(&self) -> Vec<String> {
+        let mut groups = vec![];
+        for rhyme in self.rhymes.iter().filter_map(|(_, r)| r.as_ref()) {
+            let group = rhyme.group.clone().unwrap_or(rhyme.name.clone());
+            if !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
+        groups
+    }
+}
+
+impl Display for SegmentMatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "第{}韵段：", self.segment + 1)?;
+        let slots: Vec<String> = self.rhymes.iter().map(|(slot, _)| slot_name(*slot)).collect();
+        let groups = self.groups();
+        if groups.is_empty() {
+            write!(f, "{}", slots.join("、"))?;
+        } else {
+            write!(f, "{}押{}", slots.join("、"), groups.join("、"))?;
+        }
+        let mut problems = vec![];
+        if groups.is_empty() {
+            problems.push("未押韵".to_string());
+        }
+        if !self.off_rhyme.is_empty() {
+            let chars: Vec<String> = self.off_rhyme.iter().map(|c| format!("“{}”", c)).collect();
+            problems.push(format!("{}不在此韵", chars.join("、")));
+        }
+        if self.same_as_previous {
+            problems.push("与上一韵段同部，未换韵".to_string());
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        let problems = format!("，{}", problems.join("，"));
+        if SHOULD_COLORIZE.should_colorize() {
+            write!(f, "{}", problems.red())
+        } else {
+            write!(f, "{}", problems)
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct MeterMatchResult {
     pub score: f64,
     pub result: Vec<SentenceMatchResult>,
+    /// 对偶句的对仗检查结果，不计入匹配分数
+    pub duiou: Vec<DuiouMatchResult>,
+    /// 韵书未收录的字，按出现顺序去重
+    pub unknown_chars: Vec<char>,
+    /// 按正字查韵书的异体字及其正字，按出现顺序去重
+    pub variant_chars: Vec<(char, char)>,
+    /// 推定的韵脚，格律中没有韵脚时为空
+    pub rhymes: ChosenRhymes,
+    /// 与 rhymes 分数相同的其他韵脚
+    pub tied_rhymes: Vec<ChosenRhymes>,
+    /// 须同部而不同部的韵脚，不计入匹配分数
+    pub group_mismatches: Vec<GroupMismatch>,
+    /// 换韵的词牌每个韵段的用韵情况，不换韵时为空
+    pub segments: Vec<SegmentMatch>,
+    /// 每片在 result 中的起始下标及该片的匹配分数，单片的词牌为空
+    pub stanzas: Vec<(usize, f64)>,
+    /// 韵书上去不分时，格律要求去声而未检查的字数
+    pub qu_unchecked: usize,
 }
 
 impl MeterMatchResult {
+    /// 同分韵脚中有多种选择的韵位及其可押的韵，所选的韵在前
+    pub fn tied_slot_rhymes(&self) -> Vec<(usize, Vec<Option<Arc<Rhyme>>>)> {
+        self.rhymes.0.iter().enumerate()
+            .filter_map(|(i, (slot, chosen))| {
+                let mut rhymes = vec![chosen.clone()];
+                for tied in &self.tied_rhymes {
+                    if !rhymes.contains(&tied.0[i].1) {
+                        rhymes.push(tied.0[i].1.clone());
+                    }
+                }
+                (rhymes.len() > 1).then_some((*slot, rhymes))
+            })
+            .collect()
+    }
+
     /// Count empty text lines between the first and last non-empty text lines
     fn count_empty_text_lines(&self) -> usize {
         let first_text_idx = self.result.iter().position(|r| r.text.is_some());
@@ -115,9 +515,45 @@ impl MeterMatchResult {
 impl Display for MeterMatchResult {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "匹配分数：{}", self.score)?;
-        for r in &self.result {
+        for (i, r) in self.result.iter().enumerate() {
+            if let Some(stanza_i) = self.stanzas.iter().position(|(start, _)| *start == i)
+                && let Some(name) = stanza_name(stanza_i, self.stanzas.len()) {
+                writeln!(f, "{}（匹配分数：{}）", name, self.stanzas[stanza_i].1)?;
+            }
             write!(f, "{}", r)?;
         }
+        if !self.unknown_chars.is_empty() {
+            let chars: Vec<String> = self.unknown_chars.iter().map(|c| c.to_string()).collect();
+            writeln!(f, "韵书未收录：{}", chars.join("、"))?;
+        }
+        if !self.variant_chars.is_empty() {
+            let chars: Vec<String> = self.variant_chars.iter().map(|(v, c)| format!("{}→{}", v, c)).collect();
+            writeln!(f, "异体字：{}", chars.join("、"))?;
+        }
+        if !self.rhymes.0.is_empty() {
+            writeln!(f, "韵脚：{}", self.rhymes)?;
+        }
+        // 同分的韵脚可能很多，按韵位合并列出
+        let tied = self.tied_slot_rhymes();
+        if !tied.is_empty() {
+            let slots: Vec<String> = tied.iter()
+                .map(|(slot, rhymes)| format!("{} → {}", slot_name(*slot),
+                    rhymes.iter().map(rhyme_label).collect::<Vec<_>>().join("/")))
+                .collect();
+            writeln!(f, "同分韵脚：另有{}种，{}", self.tied_rhymes.len(), slots.join("，"))?;
+        }
+        for d in &self.duiou {
+            writeln!(f, "对仗：{}", d)?;
+        }
+        for m in &self.group_mismatches {
+            writeln!(f, "{}", m)?;
+        }
+        for s in &self.segments {
+            writeln!(f, "{}", s)?;
+        }
+        if self.qu_unchecked > 0 {
+            writeln!(f, "注意：韵书上去不分，{}处要求去声的字只检查了仄声", self.qu_unchecked)?;
+        }
         Ok(())
     }
 }
@@ -161,24 +597,31 @@ impl Ord for MeterMatchResult {
 #[derive(Clone, Debug)]
 struct MeterMatchState {
     score: f64,
+    line_score: f64,
     match_result: Arc<Vec<MatchType>>,
+    tone_chars: Arc<Vec<Option<usize>>>,
+    readings: Arc<Readings>,
     text: Arc<String>,
+    offsets: Arc<[usize]>,
     meter_idx: usize,
     prev_idx: Option<(usize, usize, usize)>,
 }
 
-pub fn match_meter(rhyme_dict: &RhymeDict, input_text: &str, meter: &[Arc<[MeterTone]>],
+pub fn match_meter(rhyme_dict: &RhymeDict, input_text: &str, meter_def: &Meter,
                    for_searching: bool) -> MeterMatchResult {
-    let text = parse_input_text(input_text);
+    let lines = normalize_input(input_text);
+    let text: Vec<Arc<String>> = lines.iter().map(|l| l.text.clone()).collect();
     let text_len = text.len();
     if text_len == 0 {
-        return MeterMatchResult {
-            score: 0.0,
-            result: vec![],
-        };
+        return MeterMatchResult::default();
     }
+    let meter: Vec<Arc<[MeterTone]>> = meter_def.lines.iter()
+        .map(|line| Arc::from(line.as_slice()))
+        .collect();
+    let meter = meter.as_slice();
 
-    let possible_rhymes = get_possible_rhymes(rhyme_dict, &text, meter);
+    let possible_rhymes = get_possible_rhymes(rhyme_dict, &text, meter, &meter_def.rhyme_scheme,
+                                             meter_def.ru_rhyme);
     let mut meter_rhymes = HashSet::new();
     for meter_line in meter {
         let tone = meter_line.last();
@@ -195,63 +638,79 @@ pub fn match_meter(rhyme_dict: &RhymeDict, input_text: &str, meter: &[Arc<[Meter
 
     let mut state: Vec<Vec<Vec<Option<MeterMatchState>>>> =
         vec![vec![vec![None; rhymes_len]; meter_match_len]; text_len];
+    // 一句的匹配结果只取决于该句韵位所押的韵，韵脚分配中这些韵位相同时不必重算
+    // line_rhymes[line][rhyme_i]: 该句韵位所押的韵在该句所有不同组合中的序号
+    let line_rhymes: Vec<Vec<usize>> = meter.iter()
+        .map(|line| {
+            let slots: Vec<usize> = line.iter().filter_map(|t| t.rhyme).collect();
+            let mut keys: HashMap<Vec<Option<RhymeId>>, usize> = HashMap::new();
+            possible_rhymes.iter()
+                .map(|assignment| {
+                    let key = slots.iter().map(|&slot| assignment[slot].as_ref().map(|r| r.id)).collect();
+                    let next = keys.len();
+                    *keys.entry(key).or_insert(next)
+                })
+                .collect()
+        })
+        .collect();
 
     for text_i in 0..text_len {
+        // 上一句在各韵脚分配下，放在已扫过的位置中的最高分及其位置，随 meter_i 递增而扩展
+        let mut prev_best = vec![(0.0, None); rhymes_len];
+        let mut prev_scanned = 0;
         for meter_i in 0..meter_match_len {
+            if text_i > 0 {
+                let pre_text_i = text_i - 1;
+                /* If the current sentence is put between the rules, the last sentence
+                can be put at the same position since there can be multiple sentences
+                in the gap. If current sentence is put right at a line of the rule, the
+                previous sentence must be put in a previous position.
+                */
+                let prev_meter_i_end = if meter_i.is_multiple_of(2) { meter_i } else { meter_i - 1 };
+                while prev_scanned <= prev_meter_i_end {
+                    for (rhyme_i, best) in prev_best.iter_mut().enumerate() {
+                        // There must be a state for previous sentence, if not, there is a bug
+                        let last_match_score = state[pre_text_i][prev_scanned][rhyme_i]
+                            .as_ref().unwrap().score;
+                        if best.0 < last_match_score || (best.0 == last_match_score && best.1.is_none()) {
+                            *best = (last_match_score, Some((pre_text_i, prev_scanned, rhyme_i)));
+                        }
+                    }
+                    prev_scanned += 1;
+                }
+            }
+            let mut sentence_matches: Vec<Option<SharedSentenceMatch>> = vec![None; rhymes_len];
             for rhyme_i in 0..rhymes_len {
                 let meter_line = if meter_i.is_multiple_of(2) {
                     None
                 } else {
                     Some(meter[meter_i / 2].clone())
                 };
-                let (cur_score, cur_match) =
+                let (cur_score, cur_match, cur_tone_chars, cur_readings) =
                     if let Some(ref meter_line_val) = meter_line {
-                        let (score, result) = match_sentence(
-                            rhyme_dict,
-                            &text[text_i],
-                            meter_line_val,
-                            &possible_rhymes[rhyme_i],
-                        );
-                        (score, result)
+                        sentence_matches[line_rhymes[meter_i / 2][rhyme_i]].get_or_insert_with(|| {
+                            let (score, matches, tone_chars, readings) = match_sentence(
+                                rhyme_dict,
+                                &text[text_i],
+                                meter_line_val,
+                                &possible_rhymes[rhyme_i],
+                            );
+                            (score, Arc::new(matches), Arc::new(tone_chars), Arc::new(readings))
+                        }).clone()
                     } else {
                         // This sentence is put between/before/after the rules
-                        (0.0, vec![])
-                    };
-                let mut last_max_match_idx = None;
-                let mut last_max_score = 0.0;
-                if text_i > 0 {
-                    let pre_text_i = text_i - 1;
-
-                    let prev_meter_i_end = if meter_i.is_multiple_of(2) {
-                        /* If the current sentence is put between the rules, the last sentence
-                        can be put at the same position since there can be multiple sentences
-                        in the gap.
-                        */
-                        meter_i
-                    } else {
-                        /* if current sentence is put right at a line of the rule, the previous
-                        sentence must be put in a previous position.
-                         */
-                        meter_i - 1
+                        (0.0, Arc::new(vec![]), Arc::new(vec![]), Arc::new(vec![]))
                     };
-                    // +1 for exclusive boundary
-                    #[allow(clippy::needless_range_loop)]
-                    for prev_meter_i in 0..(prev_meter_i_end + 1) {
-                        // There must be a state for previous sentence, if not, there is a bug
-                        let last_match_score =
-                            state[pre_text_i][prev_meter_i][rhyme_i]
-                                .as_ref().unwrap().score;
-                        if last_max_score < last_match_score || (last_max_score == last_match_score && last_max_match_idx.is_none()) {
-                            last_max_score = last_match_score;
-                            last_max_match_idx = Some((pre_text_i, prev_meter_i, rhyme_i));
-                        }
-                    }
-                }
+                let (last_max_score, last_max_match_idx) = prev_best[rhyme_i];
                 let cur_state = MeterMatchState {
                     score: last_max_score + cur_score,
-                    match_result: Arc::new(cur_match),
+                    line_score: cur_score,
+                    match_result: cur_match,
+                    tone_chars: cur_tone_chars,
+                    readings: cur_readings,
                     meter_idx: meter_i,
                     text: text[text_i].clone(),
+                    offsets: lines[text_i].offsets.clone(),
                     prev_idx: last_max_match_idx,
                 };
                 state[text_i][meter_i][rhyme_i] = Some(cur_state);
@@ -262,6 +721,8 @@ pub fn match_meter(rhyme_dict: &RhymeDict, input_text: &str, meter: &[Arc<[Meter
     // get the max score for the last text line
     let mut max_score = 0.0;
     let mut max_match_idx = None;
+    // 每种韵脚分配的最高分
+    let mut rhyme_scores = vec![0.0; rhymes_len];
     for meter_i in 0..meter_match_len {
         for rhyme_i in 0..rhymes_len {
             let maybe_state = state[text_len - 1][meter_i][rhyme_i].as_ref();
@@ -273,73 +734,224 @@ pub fn match_meter(rhyme_dict: &RhymeDict, input_text: &str, meter: &[Arc<[Meter
                 max_score = state.score;
                 max_match_idx = Some((text_len - 1, meter_i, rhyme_i));
             }
+            if rhyme_scores[rhyme_i] < state.score {
+                rhyme_scores[rhyme_i] = state.score;
+            }
+        }
+    }
+    let max_match_idx = max_match_idx.unwrap();
+    let mut result = build_result_form_match_state(state, max_match_idx, meter);
+    result.duiou = match_duiou(rhyme_dict, &result.result, meter_def);
+    let assignment = &possible_rhymes[max_match_idx.2];
+    for c in text.iter().flat_map(|t| t.chars()) {
+        if !rhyme_dict.contains_char(&c) && !result.unknown_chars.contains(&c) {
+            result.unknown_chars.push(c);
+        }
+        if let Some(v) = rhyme_dict.variant_of(&c) && !result.variant_chars.contains(&(c, v)) {
+            result.variant_chars.push((c, v));
         }
     }
-    let mut result = build_result_form_match_state(state, max_match_idx.unwrap(), meter);
-    let non_empty_meter_len = meter.iter().filter(|m| !m.is_empty()).count();
+    for sentence in result.result.iter_mut() {
+        let Some(text) = &sentence.text else { continue };
+        let folded: Vec<Option<char>> = text.chars().map(|c| rhyme_dict.variant_of(&c)).collect();
+        if folded.iter().any(Option::is_some) {
+            sentence.folded = Some(Arc::new(folded));
+        }
+    }
+    let slots = present_slots(meter);
+    result.rhymes = ChosenRhymes::new(&slots, assignment);
+    result.tied_rhymes = (0..rhymes_len)
+        .filter(|&i| i != max_match_idx.2 && (rhyme_scores[i] - max_score).abs() < 1e-9)
+        .map(|i| ChosenRhymes::new(&slots, &possible_rhymes[i]))
+        .collect();
+    let actual = actual_rhymes(rhyme_dict, &result.result, &meter_def.rhyme_scheme, assignment);
+    result.group_mismatches = meter_def.rhyme_scheme.group_mismatches(&actual).into_iter()
+        .map(|(a, b)| GroupMismatch {
+            slots: (a, b),
+            rhymes: (actual[a].clone().unwrap(), actual[b].clone().unwrap()),
+            link: meter_def.rhyme_scheme.link_name(a, b),
+        })
+        .collect();
+    result.segments = match_segments(rhyme_dict, &result.result, meter, &meter_def.rhyme_scheme, &actual);
+    match_repeats(&mut result.result, meter_def);
+    if !rhyme_dict.has_qu_sheng() {
+        result.qu_unchecked = result.result.iter()
+            .filter_map(|r| Some((r, r.meter.as_ref()?)))
+            .map(|(r, meter)| (0..meter.len())
+                .filter(|&i| meter[i].tone == MeterToneType::Qu && r.char_at(i).is_some())
+                .count())
+            .sum();
+    }
+    // 可省略的句子不计入句数
+    let is_required = |m: &Arc<[MeterTone]>| !m.iter().all(|t| t.optional);
+    let required_meter_len = meter.iter().filter(|m| is_required(m)).count();
+    result.stanzas = get_stanza_scores(&result.result, meter_def, |range| {
+        meter[range].iter().filter(|m| is_required(m)).count()
+    });
     if for_searching {
         result.score /= text_len as f64;
     } else {
-        result.score /= max(text_len, non_empty_meter_len) as f64
+        result.score /= max(text_len, required_meter_len) as f64
     }
     result
 }
 
-fn parse_input_text(text: &str) -> Vec<Arc<String>> {
-    let delimiters = vec!['.', '。',  ',', '，', '、', '?', '？', '!',  '！', '\n'];
-    text
-        .split(|c| delimiters.contains(&c))
-        .map(|l| Arc::new(l.trim().to_string()))
-        .filter(|l| !l.is_empty())
-        .collect()
-}
 
-
-/// Calculate the similarity score for two sentences. If length doesn't match, the score is 0.
-/// The score should be normalized after.
+/// Calculate the similarity score for two sentences. Optional tones in the rule may be omitted:
+/// a parenthesised group is omitted or kept as a whole, a 衬字 on its own. The variant with the
+/// highest score is found by aligning the units of the rule with the chars. The score should be
+/// normalized after.
+///
+/// Returns the score, the match type of each char, the index of the char matched by each tone,
+/// and the reading used for each char.
 fn match_sentence(rhyme_dict: &RhymeDict, sentence: &str, rule: &[MeterTone],
-                  rhyme_map: &HashMap<MeterTone, Option<Arc<Rhyme>>>) -> (f64, Vec<MatchType>) {
+                  rhyme_map: &RhymeAssignment)
+        -> SentenceMatch {
+    let chars: Vec<_> = sentence.chars().collect();
+    let char_rhymes: Vec<&[Arc<Rhyme>]> = chars.iter().map(|c| rhyme_dict.get_rhymes_by_char(c)).collect();
+    // nothing to align if no tone can be omitted
+    if !rule.iter().any(|t| t.optional) {
+        let used: Vec<usize> = (0..rule.len()).collect();
+        let (score, result, readings) = match_tones(rhyme_dict, &chars, &char_rhymes, rule, &used, rhyme_map);
+        let tone_chars = (0..rule.len()).map(|i| (i < chars.len()).then_some(i)).collect();
+        return (score, result, tone_chars, readings);
+    }
+    // units of the rule: all tones of an optional group, or a single tone
+    let mut units: Vec<Range<usize>> = vec![];
+    for (i, tone) in rule.iter().enumerate() {
+        match units.last_mut() {
+            Some(last) if tone.group.is_some() && rule[last.start].group == tone.group => last.end = i + 1,
+            _ => units.push(i..i + 1),
+        }
+    }
+    // score of each tone matched with each char, computed when needed
+    let mut char_scores: Vec<Vec<Option<f64>>> = vec![vec![None; chars.len()]; rule.len()];
+    let mut char_score = |tone_idx: usize, char_idx: usize| -> f64 {
+        if char_idx >= chars.len() {
+            return 0.0;
+        }
+        *char_scores[tone_idx][char_idx].get_or_insert_with(||
+            match_char(rhyme_dict, chars[char_idx], char_rhymes[char_idx], &rule[tone_idx], rhyme_map).0)
+    };
+    // aligned[u][k]: the best total score of the first u units using k tones, and whether unit u - 1 is kept
+    let mut aligned: Vec<Vec<Option<(f64, bool)>>> = vec![vec![None; rule.len() + 1]; units.len() + 1];
+    aligned[0][0] = Some((0.0, false));
+    for (u, unit) in units.iter().enumerate() {
+        for k in 0..=rule.len() {
+            let Some((score, _)) = aligned[u][k] else { continue };
+            let kept = k + unit.len();
+            let kept_score = score + unit.clone().enumerate().map(|(t, i)| char_score(i, k + t)).sum::<f64>();
+            if aligned[u + 1][kept].is_none_or(|(best, _)| kept_score > best) {
+                aligned[u + 1][kept] = Some((kept_score, true));
+            }
+            // with the same number of tones, omitting this unit keeps an earlier one, which wins on a tie
+            if rule[unit.start].optional && aligned[u + 1][k].is_none_or(|(best, _)| score >= best) {
+                aligned[u + 1][k] = Some((score, false));
+            }
+        }
+    }
+    // the variant omitting fewer tones wins on a tie
+    let mut best_len = rule.len();
+    let mut best_score = f64::MIN;
+    for k in (0..=rule.len()).rev() {
+        if let Some((score, _)) = aligned[units.len()][k] {
+            let score = score / max(chars.len(), k) as f64;
+            if score > best_score {
+                best_score = score;
+                best_len = k;
+            }
+        }
+    }
+    let mut used = vec![];
+    let mut k = best_len;
+    for (u, unit) in units.iter().enumerate().rev() {
+        let (_, kept) = aligned[u + 1][k].unwrap();
+        if kept {
+            used.extend(unit.clone().rev());
+            k -= unit.len();
+        }
+    }
+    used.reverse();
+    let (score, result, readings) = match_tones(rhyme_dict, &chars, &char_rhymes, rule, &used, rhyme_map);
+    let mut tone_chars = vec![None; rule.len()];
+    for (char_idx, &tone_idx) in used.iter().enumerate().take(chars.len()) {
+        tone_chars[tone_idx] = Some(char_idx);
+    }
+    (score, result, tone_chars, readings)
+}
 
+/// Match chars one by one against the tones in `used`, with the readings of each char in `char_rhymes`.
+/// If length doesn't match, the extra positions are counted as no match.
+fn match_tones(rhyme_dict: &RhymeDict, chars: &[char], char_rhymes: &[&[Arc<Rhyme>]], rule: &[MeterTone],
+               used: &[usize], rhyme_map: &RhymeAssignment) -> (f64, Vec<MatchType>, Readings) {
     let mut result = vec![];
+    let mut readings = vec![];
     let mut score = 0.0;
-    let chars: Vec<_> = sentence.chars().collect();
-    let match_len = max(chars.len(), rule.len());
+    let match_len = max(char_rhymes.len(), used.len());
     for i in 0..match_len {
-        if i >= chars.len() || i >= rule.len() {
+        if i >= char_rhymes.len() || i >= used.len() {
             result.push(MatchType::NoMatch);
+            readings.push(None);
             continue;
         }
-        let rhymes = rhyme_dict.get_rhymes_by_char(&chars[i]);
-        let tone_match = rhymes.iter()
-            .any(|r| tone_match(&r.tone, &rule[i]));
-        if tone_match {
-            score += 0.8;
-        }
-        let (need_count, rhyme_match_target) = if rule[i].rhyme_num.is_none() {
-            (false, None)
-        } else {
-            (true, rhyme_map.get(&rule[i]).unwrap().clone())
-        };
-        let rhyme_match = if !need_count {
-            true
-        } else if rhyme_match_target.is_none() {
-            false
-        } else {
-            rhymes.iter().any(|r| r.deref() == rhyme_match_target.as_ref().unwrap().deref())
-        };
-        if rhyme_match {
-            score += 0.2;
-        }
-        let match_type = if rhyme_match && tone_match {
-            MatchType::AllMatch
-        } else if tone_match {
-            MatchType::ToneOnly
-        } else {
-            MatchType::NoMatch
-        };
-        result.push(match_type)
+        let (char_score, match_type, reading) = match_char(rhyme_dict, chars[i], char_rhymes[i], &rule[used[i]],
+                                                         rhyme_map);
+        score += char_score;
+        result.push(match_type);
+        readings.push(reading);
     }
-    (score / match_len as f64, result)
+    (score / match_len as f64, result, readings)
+}
+
+/// Match a char with the given readings against a tone. Returns the score, the match type and the reading used.
+fn match_char(rhyme_dict: &RhymeDict, c: char, rhymes: &[Arc<Rhyme>], tone: &MeterTone,
+              rhyme_map: &RhymeAssignment) -> (f64, MatchType, Option<Arc<Rhyme>>) {
+    // 韵书未收录的字不判断平仄和韵，按权重计分
+    if rhymes.is_empty() {
+        return (rhyme_dict.unknown_weight(), MatchType::Unknown, None);
+    }
+    let mut score = 0.0;
+    let first_tone_match = tone_match(&rhymes[0].tone, tone);
+    let tone_match = rhymes.iter()
+        .any(|r| tone_match(&r.tone, tone));
+    if tone_match {
+        score += 0.8;
+    }
+    let (need_count, rhyme_match_target) = match tone.rhyme {
+        None => (false, None),
+        Some(slot) => (true, rhyme_map[slot].clone()),
+    };
+    let rhyme_match = if !need_count {
+        true
+    } else if rhyme_match_target.is_none() {
+        false
+    } else {
+        rhymes.iter().any(|r| r.deref() == rhyme_match_target.as_ref().unwrap().deref())
+    };
+    if rhyme_match {
+        score += 0.2;
+    }
+    // 韵书区分去声时，检查要求去声处是否用了去声。不影响分数
+    let qu_match = tone.tone != MeterToneType::Qu || !rhyme_dict.has_qu_sheng() || rhyme_dict.is_qu_sheng(&c);
+    // 所取的读音：韵脚取所押的韵，其他取与格律平仄相合的读音
+    let reading = rhymes.iter()
+        .find(|r| rhyme_match_target.as_ref().is_some_and(|t| r.id == t.id))
+        .or_else(|| rhymes.iter().find(|r| tone.tone.basic().as_ref() == Some(&r.tone)))
+        .or(rhymes.first());
+    // 首选读音不合格律，取了另一声调的读音才相合
+    let ambiguous = !first_tone_match && reading.is_some_and(|r| r.tone != rhymes[0].tone);
+    let match_type = if rhyme_match && tone_match && qu_match && ambiguous {
+        MatchType::Ambiguous
+    } else if rhyme_match && tone_match && qu_match {
+        MatchType::AllMatch
+    } else if rhyme_match && tone_match {
+        MatchType::NotQu
+    } else if tone_match {
+        MatchType::ToneOnly
+    } else {
+        MatchType::NoMatch
+    };
+    (score, match_type, reading.cloned())
 }
 
 fn build_result_form_match_state(state: Vec<Vec<Vec<Option<MeterMatchState>>>>,
@@ -351,26 +963,43 @@ fn build_result_form_match_state(state: Vec<Vec<Vec<Option<MeterMatchState>>>>,
     let mut cur_meter_idx = (meter.len() - 1) as isize;
     while maybe_cur_state.is_some() {
         let cur_state = maybe_cur_state.unwrap();
-        while cur_meter_idx > (cur_state.meter_idx / 2) as isize {
+        // a text put at line k consumes line k, a text put in the gap before line k consumes nothing
+        let last_line_before = if cur_state.meter_idx.is_multiple_of(2) {
+            (cur_state.meter_idx / 2) as isize - 1
+        } else {
+            (cur_state.meter_idx / 2) as isize
+        };
+        while cur_meter_idx > last_line_before {
             result.push(
                 SentenceMatchResult{
                     match_result: None,
                     text: None,
                     meter: Some(meter[cur_meter_idx as usize].clone()),
+                    tone_chars: None,
+                    score: 0.0,
+                    readings: None,
+                    offsets: None,
+                    folded: None,
                 }
             );
             cur_meter_idx -= 1;
         }
-        cur_meter_idx -= 1;
         let meter_line = if cur_state.meter_idx.is_multiple_of(2) {
             None
         } else {
+            cur_meter_idx -= 1;
             Some(meter[cur_state.meter_idx / 2].clone())
         };
+        let tone_chars = meter_line.as_ref().map(|_| cur_state.tone_chars.clone());
         let sentence_match_result = SentenceMatchResult{
             match_result: Some(cur_state.match_result.clone()),
             text: Some(cur_state.text.clone()),
+            score: if meter_line.is_some() { cur_state.line_score } else { 0.0 },
             meter: meter_line,
+            tone_chars,
+            readings: Some(cur_state.readings.clone()),
+            offsets: Some(cur_state.offsets.clone()),
+            folded: None,
         };
         result.push(sentence_match_result);
         maybe_cur_state = cur_state.prev_idx.and_then( |prev_idx|
@@ -378,15 +1007,231 @@ fn build_result_form_match_state(state: Vec<Vec<Vec<Option<MeterMatchState>>>>,
     }
     while cur_meter_idx >= 0 {
         result.push(SentenceMatchResult { match_result: None, text: None,
-            meter: Some(meter[cur_meter_idx as usize].clone()) });
+            meter: Some(meter[cur_meter_idx as usize].clone()), tone_chars: None, score: 0.0, readings: None,
+            offsets: None, folded: None });
         cur_meter_idx -= 1;
     }
     result.reverse();
-    MeterMatchResult {score, result}
+    MeterMatchResult { score, result, ..Default::default() }
 }
 
-fn get_possible_rhymes(rhyme_dict: &RhymeDict, text: &[Arc<String>], meter: &[Arc<[MeterTone]>]
-        ) -> Vec<HashMap<MeterTone, Option<Arc<Rhyme>>>> {
+/// 计算每片的匹配分数。句间的文本归入前一句所在的片，开头的归入第一片。
+/// 每片分数按该片文本句数与必需格律句数的较大者归一化
+fn get_stanza_scores(result: &[SentenceMatchResult], meter: &Meter,
+                     required_len: impl Fn(Range<usize>) -> usize) -> Vec<(usize, f64)> {
+    let stanzas = meter.stanzas();
+    if stanzas.len() < 2 {
+        return vec![];
+    }
+    let mut starts = vec![0];
+    let mut meter_idx = 0;
+    for (i, r) in result.iter().enumerate() {
+        if r.meter.is_none() {
+            continue;
+        }
+        if meter_idx > 0 && meter.stanza_starts.contains(&meter_idx) {
+            starts.push(i);
+        }
+        meter_idx += 1;
+    }
+    let mut ends: Vec<usize> = starts.iter().skip(1).cloned().collect();
+    ends.push(result.len());
+    starts.iter().zip(ends).zip(stanzas)
+        .map(|((&start, end), range)| {
+            let entries = &result[start..end];
+            let score: f64 = entries.iter().map(|r| r.score).sum();
+            let text_len = entries.iter().filter(|r| r.text.is_some()).count();
+            let len = max(text_len, required_len(range));
+            (start, if len == 0 { 0.0 } else { score / len as f64 })
+        })
+        .collect()
+}
+
+/// 将文本单独与每一片匹配，结果按匹配程度从高到低排列，并附有所匹配片的序号
+pub fn match_stanza(rhyme_dict: &RhymeDict, input_text: &str, meter_def: &Meter)
+        -> Vec<(usize, MeterMatchResult)> {
+    let mut results: Vec<(usize, MeterMatchResult)> = (0..meter_def.stanzas().len())
+        .map(|i| (i, match_meter(rhyme_dict, input_text, &meter_def.stanza(i), false)))
+        .collect();
+    results.sort_by(|a, b| a.1.cmp(&b.1));
+    results
+}
+
+/// 检查对偶句是否对仗：字数相同，格律要求平仄相对处平仄相对，且两句没有重字
+fn match_duiou(rhyme_dict: &RhymeDict, result: &[SentenceMatchResult], meter: &Meter)
+        -> Vec<DuiouMatchResult> {
+    // every meter line appears exactly once in the result, in order
+    let lines: Vec<&SentenceMatchResult> = result.iter()
+        .filter(|r| r.meter.is_some())
+        .collect();
+    // 领格字、衬字不在对偶之内
+    let core_positions = |line_idx: usize| -> Vec<(&MeterTone, Option<char>)> {
+        meter.lines[line_idx].iter()
+            .enumerate()
+            .filter(|(_, t)| t.kind == MeterToneKind::Normal)
+            .map(|(i, t)| (t, lines[line_idx].char_at(i)))
+            .collect()
+    };
+    let core_chars = |line_idx: usize, text: &str| -> Vec<char> {
+        let tone_chars = lines[line_idx].tone_chars.as_ref();
+        let excluded: Vec<usize> = meter.lines[line_idx].iter()
+            .enumerate()
+            .filter(|(_, t)| t.kind != MeterToneKind::Normal)
+            .filter_map(|(i, _)| tone_chars.and_then(|c| c[i]))
+            .collect();
+        text.chars().enumerate().filter(|(i, _)| !excluded.contains(i)).map(|(_, c)| c).collect()
+    };
+    meter.duiou.iter()
+        .filter_map(|&(a, b)| {
+            let text_a = lines.get(a)?.text.clone()?;
+            let text_b = lines.get(b)?.text.clone()?;
+            let positions_a = core_positions(a);
+            let positions_b = core_positions(b);
+            let chars_a = core_chars(a, &text_a);
+            let chars_b = core_chars(b, &text_b);
+            let tone_mismatch = positions_a.iter()
+                .zip(positions_b.iter())
+                .enumerate()
+                .filter(|(_, ((ta, _), (tb, _)))| {
+                    ta.tone.basic().is_some() && tb.tone.basic().is_some() && ta.tone.basic() != tb.tone.basic()
+                })
+                .filter_map(|(i, ((_, ca), (_, cb)))| Some((i, (*ca)?, (*cb)?)))
+                .filter(|(_, ca, cb)| !is_tone_opposed(rhyme_dict, ca, cb))
+                .map(|(i, _, _)| i + 1)
+                .collect();
+            let mut repeated_chars = vec![];
+            for c in &chars_a {
+                if chars_b.contains(c) && !repeated_chars.contains(c) {
+                    repeated_chars.push(*c);
+                }
+            }
+            Some(DuiouMatchResult {
+                length_match: chars_a.len() == chars_b.len(),
+                text: (text_a, text_b),
+                tone_mismatch,
+                repeated_chars,
+            })
+        })
+        .collect()
+}
+
+/// 检查叠句、叠韵处是否重复了前面的字，未重复而没有其他错误的字标记为 MatchType::RepeatMismatch。
+/// 叠字检查不影响匹配分数
+fn match_repeats(result: &mut [SentenceMatchResult], meter: &Meter) {
+    // every meter line appears exactly once in the result, in order
+    let line_entries: Vec<usize> = result.iter()
+        .enumerate()
+        .filter(|(_, r)| r.meter.is_some())
+        .map(|(i, _)| i)
+        .collect();
+    for repeat in &meter.repeats {
+        for (&source, &target) in repeat.source.iter().zip(repeat.target.iter()) {
+            let source_entry = &result[line_entries[source.0]];
+            let target_entry = &result[line_entries[target.0]];
+            let (Some(source_char), Some(target_char)) =
+                (source_entry.char_at(source.1), target_entry.char_at(target.1)) else {
+                continue;
+            };
+            if source_char == target_char {
+                continue;
+            }
+            let char_idx = target_entry.tone_chars.as_ref().and_then(|c| c[target.1]);
+            let target_entry = &mut result[line_entries[target.0]];
+            // 平仄、韵脚等已有错误时保留原错误，只标记其他方面都相合的字
+            if let (Some(match_result), Some(char_idx)) = (target_entry.match_result.as_mut(), char_idx)
+                && let Some(m) = Arc::make_mut(match_result).get_mut(char_idx)
+                && matches!(m, MatchType::AllMatch | MatchType::Ambiguous | MatchType::Unknown) {
+                *m = MatchType::RepeatMismatch;
+            }
+        }
+    }
+}
+
+/// 各韵位的韵脚字实际所押的韵：所分配的韵不能涵盖全部韵脚字时，取韵脚字共有的同声韵，没有则仍用所分配的韵。
+/// 分配韵时已剪去不符合须同部、须换部要求的分配，借此报告韵脚字实际押了别部或未换部的情况
+fn actual_rhymes(rhyme_dict: &RhymeDict, result: &[SentenceMatchResult], scheme: &RhymeScheme,
+                 assignment: &RhymeAssignment) -> RhymeAssignment {
+    let in_rhyme = |c: &char, rhyme: &Rhyme| rhyme_dict.get_rhymes_by_char(c).iter().any(|r| r.id == rhyme.id);
+    (0..scheme.slots.len()).map(|slot| {
+        let chars: Vec<char> = result.iter()
+            .filter_map(|r| Some((r, r.meter.as_ref()?)))
+            .flat_map(|(r, meter)| (0..meter.len())
+                .filter(|&i| meter[i].rhyme == Some(slot))
+                .filter_map(|i| r.char_at(i)))
+            .collect();
+        if let Some(rhyme) = &assignment[slot] && chars.iter().all(|c| in_rhyme(c, rhyme)) {
+            return Some(rhyme.clone());
+        }
+        chars.first().and_then(|first| rhyme_dict.get_rhymes_by_char(first).iter()
+            .filter(|r| r.tone == scheme.slots[slot].tone && chars.iter().all(|c| in_rhyme(c, r)))
+            .min_by_key(|r| r.id)
+            .cloned())
+            .or_else(|| assignment[slot].clone())
+    }).collect()
+}
+
+/// 检查换韵的词牌每个韵段所押的韵，只有一个韵段时不检查。格律中没有的韵段（如单独匹配一片时）不列出
+fn match_segments(rhyme_dict: &RhymeDict, result: &[SentenceMatchResult], meter: &[Arc<[MeterTone]>],
+                  scheme: &RhymeScheme, assignment: &RhymeAssignment) -> Vec<SegmentMatch> {
+    if scheme.segment_count() < 2 {
+        return vec![];
+    }
+    let unchanged = scheme.unchanged_segments(meter, assignment);
+    let mut segments: Vec<SegmentMatch> = vec![];
+    for segment in 0..scheme.segment_count() {
+        let slots = scheme.segment_slots(segment);
+        let mut present = false;
+        let mut off_rhyme = vec![];
+        for r in result {
+            let Some(meter) = &r.meter else { continue };
+            for (i, tone) in meter.iter().enumerate() {
+                let Some(slot) = tone.rhyme.filter(|s| slots.contains(s)) else { continue };
+                present = true;
+                // 未押韵的韵位不逐字列出
+                let (Some(c), Some(target)) = (r.char_at(i), &assignment[slot]) else { continue };
+                let in_rhyme = rhyme_dict.get_rhymes_by_char(&c).iter().any(|r| r.id == target.id);
+                if !in_rhyme && !off_rhyme.contains(&c) {
+                    off_rhyme.push(c);
+                }
+            }
+        }
+        if !present {
+            continue;
+        }
+        segments.push(SegmentMatch {
+            segment,
+            rhymes: slots.iter().map(|&slot| (slot, assignment[slot].clone())).collect(),
+            off_rhyme,
+            same_as_previous: unchanged.contains(&segment),
+        });
+    }
+    segments
+}
+
+/// 两字平仄是否相对。不在韵书中的字不做判断，多音字只要有一种读音相对即可
+fn is_tone_opposed(rhyme_dict: &RhymeDict, a: &char, b: &char) -> bool {
+    let tones_a: HashSet<&BasicTone> = rhyme_dict.get_rhymes_by_char(a).iter().map(|r| &r.tone).collect();
+    let tones_b: HashSet<&BasicTone> = rhyme_dict.get_rhymes_by_char(b).iter().map(|r| &r.tone).collect();
+    if tones_a.is_empty() || tones_b.is_empty() {
+        return true;
+    }
+    !(tones_a.len() == 1 && tones_a == tones_b)
+}
+
+/// 格律中出现的韵位
+fn present_slots(meter: &[Arc<[MeterTone]>]) -> Vec<usize> {
+    let mut slots: Vec<usize> = meter.iter()
+        .flat_map(|line| line.iter().filter_map(|t| t.rhyme))
+        .collect();
+    slots.sort();
+    slots.dedup();
+    slots
+}
+
+/// 列出所有可能的韵脚分配。要求入声韵且韵书单列入声时，仄韵只用入声韵部
+fn get_possible_rhymes(rhyme_dict: &RhymeDict, text: &[Arc<String>], meter: &[Arc<[MeterTone]>],
+                       scheme: &RhymeScheme, ru_rhyme: bool) -> Vec<RhymeAssignment> {
+    let ru_only = ru_rhyme && rhyme_dict.has_ru_sheng();
     let last_chars: Vec<char> = text.iter()
         .filter_map(|s| s.chars().last()).collect();
     let mut ping_set = HashSet::new();
@@ -395,39 +1240,34 @@ fn get_possible_rhymes(rhyme_dict: &RhymeDict, text: &[Arc<String>], meter: &[Ar
         for rhyme in rhyme_dict.get_rhymes_by_char(&c) {
             if rhyme.tone == BasicTone::Ping {
                 ping_set.insert(rhyme.clone());
-            } else {
+            } else if !ru_only || rhyme.sheng_diao == ShengDiao::Ru {
                 ze_set.insert(rhyme.clone());
             }
         }
     }
-    let mut meter_tone_set = HashSet::new();
-    for meter_line in meter {
-        for meter_tone in meter_line.iter() {
-            if meter_tone.rhyme_num.is_some() {
-                meter_tone_set.insert(meter_tone);
-            }
-        }
-    }
-
-    let mut results = Vec::new();
-    let meter_tones: Vec<MeterTone> = meter_tone_set.into_iter().cloned().collect();
+    // 按韵的编号排序，使分配的顺序固定，分数相同时结果不变
+    let mut ping_rhymes: Vec<Arc<Rhyme>> = ping_set.into_iter().collect();
+    ping_rhymes.sort_by_key(|r| r.id);
+    let mut ze_rhymes: Vec<Arc<Rhyme>> = ze_set.into_iter().collect();
+    ze_rhymes.sort_by_key(|r| r.id);
+    // 只分配格律中出现的韵位，如单独匹配一片时
+    let slots = present_slots(meter);
 
-    if meter_tones.is_empty() {
+    if slots.is_empty() {
         return vec![];
     }
 
-    let mut current = HashMap::new();
-    let mut rhyme_num_groups: HashMap<i32, Option<String>> = HashMap::new();
-
-    let mut used_rhymes = HashSet::new();
+    let mut results = Vec::new();
+    let mut current = vec![None; scheme.slots.len()];
+    let adjacent = scheme.adjacent_segments(meter);
     dfs_rhyme_combines(
-        &meter_tones,
+        scheme,
+        &slots,
+        &adjacent,
         0,
         &mut current,
-        &mut rhyme_num_groups,
-        &ping_set,
-        &ze_set,
-        &mut used_rhymes,
+        &ping_rhymes,
+        &ze_rhymes,
         &mut results,
     );
 
@@ -437,77 +1277,60 @@ fn get_possible_rhymes(rhyme_dict: &RhymeDict, text: &[Arc<String>], meter: &[Ar
 /// DFS to explore all valid rhyme combinations
 #[allow(clippy::too_many_arguments)]
 fn dfs_rhyme_combines(
-    meter_tones: &[MeterTone],
+    scheme: &RhymeScheme,
+    slots: &[usize],
+    adjacent: &[(usize, usize)],
     index: usize,
-    current: &mut HashMap<MeterTone, Option<Arc<Rhyme>>>,
-    rhyme_num_groups: &mut HashMap<i32, Option<String>>,
-    ping_rhymes: &HashSet<Arc<Rhyme>>,
-    ze_rhymes: &HashSet<Arc<Rhyme>>,
-    used_rhymes: &mut HashSet<Arc<Rhyme>>,
-    results: &mut Vec<HashMap<MeterTone, Option<Arc<Rhyme>>>>,
+    current: &mut RhymeAssignment,
+    ping_rhymes: &[Arc<Rhyme>],
+    ze_rhymes: &[Arc<Rhyme>],
+    results: &mut Vec<RhymeAssignment>,
 ) {
-    // Base case: all meter_tones have been assigned
-    if index == meter_tones.len() {
+    // Base case: all slots have been assigned
+    if index == slots.len() {
         results.push(current.clone());
         return;
     }
 
-    let meter_tone = &meter_tones[index];
+    let slot = slots[index];
 
-    // Option 1: Assign None to this meter_tone
-    current.insert(meter_tone.clone(), None);
+    // Option 1: Assign None to this slot
     dfs_rhyme_combines(
-        meter_tones,
+        scheme,
+        slots,
+        adjacent,
         index + 1,
         current,
-        rhyme_num_groups,
         ping_rhymes,
         ze_rhymes,
-        used_rhymes,
         results,
     );
-    current.remove(meter_tone);
 
-    // Option 2: Assign a rhyme to this meter_tone
-    let available_rhymes = match meter_tone.tone {
-        MeterToneType::Ping => ping_rhymes,
-        MeterToneType::Ze => ze_rhymes,
-        MeterToneType::Zhong => panic!("MeterToneType::Zhong should not appear in meter patterns"),
+    // Option 2: Assign a rhyme to this slot
+    let available_rhymes = match scheme.slots[slot].tone {
+        BasicTone::Ping => ping_rhymes,
+        BasicTone::Ze => ze_rhymes,
     };
 
+    // 不同韵位可以押同一韵，如菩萨蛮第一、三韵段。不符合须同部、须换部要求的分配直接剪去
     for rhyme in available_rhymes {
-        // Skip if rhyme is already used
-        if used_rhymes.contains(rhyme) {
+        if scheme.conflicts(slot, rhyme, adjacent, current) {
             continue;
         }
-
-        let rhyme_group = rhyme.group.clone();
-
-        // Validate: if this meter_tone has a rhyme_num with an existing group, they must match
-        let rhyme_num = meter_tone.rhyme_num.unwrap();
-        rhyme_num_groups.entry(rhyme_num).or_insert_with(|| rhyme_group.clone());
-        if rhyme_num_groups.get(&rhyme_num).unwrap() != &rhyme_group {
-            continue;
-        }
-        current.insert(meter_tone.clone(), Some(rhyme.clone()));
-
-        // Mark rhyme as used
-        used_rhymes.insert(rhyme.clone());
+        current[slot] = Some(rhyme.clone());
 
         dfs_rhyme_combines(
-            meter_tones,
+            scheme,
+            slots,
+            adjacent,
             index + 1,
             current,
-            rhyme_num_groups,
             ping_rhymes,
             ze_rhymes,
-            used_rhymes,
             results,
         );
 
         // clean up state after recursive call
-        used_rhymes.remove(rhyme);
-        rhyme_num_groups.remove(&rhyme_num);
-        current.remove(meter_tone);
+        current[slot] = None;
     }
 }
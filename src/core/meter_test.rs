@@ -0,0 +1,494 @@
+use crate::core::cipai::{best_match, rank_variants, CiPai};
+use crate::core::input::strip_title;
+use crate::core::meter::{match_meter, match_stanza, MatchType};
+use crate::core::rhyme::RhymeDict;
+use crate::core::tone::{BasicTone, ShengDiao};
+use crate::parser::cipai_parser::parse_cipai;
+use crate::parser::rhyme_parser::{parse_cilin, parse_pingshui};
+
+fn cilin_dict() -> RhymeDict {
+    let content = std::fs::read_to_string("data/rhyme/Cilin_Rhyme.json").expect("Should read data file");
+    parse_cilin(&content).expect("Should parse successfully")
+}
+
+fn find_cipai(name: &str, variant: &str) -> CiPai {
+    let content = std::fs::read_to_string("data/cipai/cipai.xml").expect("Should read data file");
+    parse_cipai(&content)
+        .expect("Should parse successfully")
+        .into_iter()
+        .find(|c| c.names[0] == name && c.variant.as_deref() == Some(variant))
+        .expect("CiPai should exist")
+}
+
+#[test]
+fn test_match_duiou() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("渔歌子", "定格");
+    let result = match_meter(&rhyme_dict, "西塞山前白鹭飞，桃花流水鳜鱼肥。青箬笠，绿蓑衣，斜风细雨不须归。",
+                             &cipai.meter, false);
+    assert_eq!(result.duiou.len(), 1);
+    assert!(result.duiou[0].is_match());
+    assert_eq!(result.duiou[0].text.0.as_str(), "青箬笠");
+    assert_eq!(result.duiou[0].text.1.as_str(), "绿蓑衣");
+}
+
+#[test]
+fn test_match_duiou_mismatch() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("渔歌子", "定格");
+    let result = match_meter(&rhyme_dict, "西塞山前白鹭飞，桃花流水鳜鱼肥。青箬笠，青蓑笠，斜风细雨不须归。",
+                             &cipai.meter, false);
+    let duiou = &result.duiou[0];
+    assert!(!duiou.is_match());
+    assert!(duiou.length_match);
+    assert_eq!(duiou.tone_mismatch, vec![1, 3]);
+    assert_eq!(duiou.repeated_chars, vec!['青', '笠']);
+}
+
+#[test]
+fn test_match_repeats() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("如梦令", "定格");
+    let text = "昨夜雨疏风骤，浓睡不消残酒。试问卷帘人，却道海棠依旧。知否，知否，应是绿肥红瘦。";
+    let result = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    assert!(result.result.iter()
+        .filter_map(|r| r.match_result.as_ref())
+        .all(|m| !m.contains(&MatchType::RepeatMismatch)));
+
+    let text = "昨夜雨疏风骤，浓睡不消残酒。试问卷帘人，却道海棠依旧。知否，如否，应是绿肥红瘦。";
+    let result = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    let line = result.result.iter().find(|r| r.text.as_deref().map(|t| t.as_str()) == Some("如否")).unwrap();
+    let match_result = line.match_result.as_ref().unwrap();
+    assert_eq!(match_result[0], MatchType::RepeatMismatch);
+    assert_ne!(match_result[1], MatchType::RepeatMismatch);
+
+    // “道”不押韵，保留韵脚错误，不改为叠字错误
+    let text = "昨夜雨疏风骤，浓睡不消残酒。试问卷帘人，却道海棠依旧。知否，知道，应是绿肥红瘦。";
+    let result = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    let line = result.result.iter().find(|r| r.text.as_deref().map(|t| t.as_str()) == Some("知道")).unwrap();
+    let match_result = line.match_result.as_ref().unwrap();
+    assert_ne!(match_result[0], MatchType::RepeatMismatch);
+    assert_eq!(match_result[1], MatchType::ToneOnly);
+}
+
+const OPTIONAL_CIPAI: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<唐宋词格律><类别><名称>平韵格</名称><词牌><名称>测试令</名称><正文>
+<格律 说明="定格"><![CDATA[＋│（│）－－％，
+（－－│）。
+＋││－－％。]]></格律></正文></词牌></类别></唐宋词格律>
+"#;
+
+#[test]
+fn test_match_optional() {
+    let rhyme_dict = cilin_dict();
+    let cipai = parse_cipai(OPTIONAL_CIPAI).unwrap().remove(0);
+
+    // 省略可省略的字和句均不扣分
+    let full = match_meter(&rhyme_dict, "风景旧曾谙，江南好，风景旧曾谙。", &cipai.meter, false);
+    let omitted = match_meter(&rhyme_dict, "风景曾谙，风景旧曾谙。", &cipai.meter, false);
+    assert_eq!(full.score, 1.0);
+    assert_eq!(omitted.score, 1.0);
+
+    let first = &omitted.result[0];
+    assert_eq!(first.tone_chars.as_ref().unwrap().as_slice(), &[Some(0), Some(1), None, Some(2), Some(3)]);
+    assert!(omitted.result[1].text.is_none(), "Optional line should be left empty");
+    assert_eq!(omitted.result.len(), 3);
+}
+
+/// 只有一句的格律
+fn single_line_cipai(meter: &str) -> CiPai {
+    parse_cipai(&format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<唐宋词格律><类别><名称>平韵格</名称><词牌><名称>测试令</名称><正文>
+<格律 说明="定格"><![CDATA[{}]]></格律></正文></词牌></类别></唐宋词格律>
+"#, meter)).unwrap().remove(0)
+}
+
+#[test]
+fn test_match_optional_group() {
+    let rhyme_dict = cilin_dict();
+    let cipai = single_line_cipai("－－（││）－％。");
+    assert_eq!(match_meter(&rhyme_dict, "东风去去东。", &cipai.meter, false).score, 1.0);
+    assert_eq!(match_meter(&rhyme_dict, "东风东。", &cipai.meter, false).score, 1.0);
+
+    // 可省略的一组字须一并省略或保留，不能只省略其中一字
+    let result = match_meter(&rhyme_dict, "东风去东。", &cipai.meter, false);
+    assert!(result.score < 1.0);
+    let tone_chars = result.result[0].tone_chars.as_ref().unwrap();
+    assert_eq!(tone_chars[2].is_some(), tone_chars[3].is_some());
+}
+
+#[test]
+fn test_match_many_chenzi() {
+    let rhyme_dict = cilin_dict();
+    // 每个衬字可单独省略，衬字多时也不必逐一枚举
+    let cipai = single_line_cipai(&format!("＋│{}│－－％。", "ˇ".repeat(70)));
+    let result = match_meter(&rhyme_dict, "风景旧曾谙。", &cipai.meter, false);
+    assert_eq!(result.score, 1.0);
+    let result = match_meter(&rhyme_dict, "风景真旧曾谙。", &cipai.meter, false);
+    assert_eq!(result.score, 1.0);
+    assert_eq!(result.result[0].char_at(2), Some('真'));
+}
+
+#[test]
+fn test_match_chenzi() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("忆江南", "定格");
+    let without = match_meter(&rhyme_dict, "江南好，风景旧曾谙。日出江花红胜火，春来江水绿如蓝。能不忆江南。",
+                              &cipai.meter, false);
+    let with = match_meter(&rhyme_dict, "江南好，风景真旧曾谙。日出江花红胜火，春来江水绿如蓝。能不忆江南。",
+                           &cipai.meter, false);
+    assert_eq!(without.score, with.score);
+    assert_eq!(with.result[1].char_at(2), Some('真'));
+    assert_eq!(without.result[1].char_at(2), None);
+}
+
+#[test]
+fn test_match_dou() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("八声甘州", "定格");
+    let text = "对潇潇暮雨洒江天，一番洗清秋。渐霜风凄紧，关河冷落，残照当楼。是处红衰翠减，苒苒物华休。\
+        惟有长江水，无语东流。不忍登高临远，望故乡渺邈，归思难收。叹年来踪迹，何事苦淹留？\
+        想佳人妆楼颙望，误几回、天际识归舟。争知我、倚阑干处，正恁凝愁！";
+    let with_dou = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    let without_dou = match_meter(&rhyme_dict, &text.replace('、', ""), &cipai.meter, false);
+    assert_eq!(with_dou.score, without_dou.score);
+    let lines = with_dou.result.iter().filter(|r| r.text.is_some()).count();
+    assert_eq!(lines, cipai.meter.lines.iter().filter(|l| !l.is_empty()).count());
+}
+
+#[test]
+fn test_match_stanzas() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("南歌子", "双调");
+    let result = match_meter(&rhyme_dict,
+                             "手里金鹦鹉，胸前绣凤凰。偷眼暗形相，不如从嫁与，作鸳鸯。\
+                              手里金鹦鹉，胸前绣凤凰。偷眼暗形相，不如从嫁与，作鸳鸯。",
+                             &cipai.meter, false);
+    assert_eq!(result.stanzas.len(), 2);
+    assert_eq!(result.stanzas[0].0, 0);
+    assert!((result.stanzas[0].1 - result.stanzas[1].1).abs() < 1e-9);
+    assert!((result.stanzas[0].1 - result.score).abs() < 1e-9);
+}
+
+#[test]
+fn test_match_single_stanza() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("南歌子", "双调");
+    let text = "手里金鹦鹉，胸前绣凤凰。偷眼暗形相，不如从嫁与，作鸳鸯。";
+    let results = match_stanza(&rhyme_dict, text, &cipai.meter);
+    assert_eq!(results.len(), 2);
+    assert!(results[0].1.stanzas.is_empty());
+    // 单独与一片匹配，比与全词匹配的分数高
+    let full = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    assert!(results[0].1.score > full.score);
+}
+
+#[test]
+fn test_match_ru_rhyme() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("念奴娇", "变格一");
+    assert!(cipai.meter.ru_rhyme);
+    let text = "大江东去，浪淘尽、千古风流人物。故垒西边，人道是、三国周郎赤壁。乱石穿空，惊涛拍岸，卷起千堆雪。\
+                江山如画，一时多少豪杰。遥想公瑾当年，小乔初嫁了，雄姿英发。羽扇纶巾，谈笑间、樯橹灰飞烟灭。\
+                故国神游，多情应笑我，早生华发。人生如梦，一尊还酹江月。";
+    let result = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    let first = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.ends_with('物'))).unwrap();
+    assert_eq!(first.match_result.as_ref().unwrap().last(), Some(&MatchType::AllMatch));
+
+    // “处”是去声，不能押入声韵
+    let text = text.replacen("人物", "人处", 1);
+    let result = match_meter(&rhyme_dict, &text, &cipai.meter, false);
+    let first = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.ends_with('处'))).unwrap();
+    assert_eq!(first.match_result.as_ref().unwrap().last(), Some(&MatchType::ToneOnly));
+
+    // 不要求入声韵时，“处”与其他韵脚不同部，也是韵脚错，但入声韵脚仍然正确
+    let mut meter = cipai.meter.clone();
+    meter.ru_rhyme = false;
+    let result = match_meter(&rhyme_dict, &text, &meter, false);
+    let second = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.ends_with('杰'))).unwrap();
+    assert_eq!(second.match_result.as_ref().unwrap().last(), Some(&MatchType::AllMatch));
+}
+
+#[test]
+fn test_match_qu() {
+    let content = std::fs::read_to_string("data/rhyme/Pingshui_Rhyme.json").expect("Should read data file");
+    let rhyme_dict = parse_pingshui(&content).expect("Should parse successfully");
+    let cipai = find_cipai("太常引", "定格");
+    let text = "一轮秋影转金波。飞镜又重磨。把酒问姮娥，被白发、欺人奈何？\
+                乘风好去，长空万里，直下看山河。斫去桂婆娑，人道是、清光更多。";
+    let result = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    let line = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.ends_with("人奈何"))).unwrap();
+    assert_eq!(line.match_result.as_ref().unwrap()[5], MatchType::AllMatch);
+
+    // “有”是上声，不是去声
+    let text = text.replacen("奈何", "有何", 1);
+    let result = match_meter(&rhyme_dict, &text, &cipai.meter, false);
+    let line = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.ends_with("人有何"))).unwrap();
+    assert_eq!(line.match_result.as_ref().unwrap()[5], MatchType::NotQu);
+
+    // 词林正韵上去不分，不检查去声，并提示未检查
+    let result = match_meter(&cilin_dict(), &text, &cipai.meter, false);
+    let line = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.ends_with("人有何"))).unwrap();
+    assert_eq!(line.match_result.as_ref().unwrap()[5], MatchType::AllMatch);
+    assert!(result.qu_unchecked > 0);
+    assert!(result.to_string().contains("只检查了仄声"));
+
+    // 按平水韵补充去声字后，词林正韵也检查去声
+    let cilin = cilin_dict().with_qu_chars(rhyme_dict.chars_by_sheng_diao(ShengDiao::Qu));
+    let result = match_meter(&cilin, &text, &cipai.meter, false);
+    let line = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.ends_with("人有何"))).unwrap();
+    assert_eq!(line.match_result.as_ref().unwrap()[5], MatchType::NotQu);
+    assert_eq!(result.qu_unchecked, 0);
+}
+
+#[test]
+fn test_match_tongye_group_mismatch() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("西江月", "定格");
+    let text = "明月别枝惊鹊，清风半夜鸣蝉。稻花香里说丰年，听取蛙声一片。\
+                七八个星天外，两三点雨山前。旧时茅店社林边，路转溪桥忽见。";
+    let result = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    assert!(result.group_mismatches.is_empty());
+
+    // “阵”、“进”在第六部，与平韵第七部不同部，不算押韵，并报告实际所押的韵部
+    let text = text.replace("一片", "一阵").replace("忽见", "忽进");
+    let result = match_meter(&rhyme_dict, &text, &cipai.meter, false);
+    let last = result.result.iter().rfind(|r| r.text.is_some()).unwrap();
+    assert_eq!(last.match_result.as_ref().unwrap().last(), Some(&MatchType::ToneOnly));
+    assert_eq!(result.group_mismatches.len(), 1);
+    let mismatch = &result.group_mismatches[0];
+    assert_eq!(mismatch.slots, (0, 1));
+    assert_eq!(mismatch.rhymes.0.group.as_deref(), Some("第七部"));
+    assert_eq!(mismatch.rhymes.1.group.as_deref(), Some("第六部"));
+    assert_eq!(mismatch.link, "通叶");
+}
+
+#[test]
+fn test_match_segments() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("菩萨蛮", "定格");
+    let text = "平林漠漠烟如织，寒山一带伤心碧。暝色入高楼，有人楼上愁。\
+                玉阶空伫立，宿鸟归飞急。何处是归程，长亭更短亭。";
+    let result = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    let groups: Vec<Vec<String>> = result.segments.iter().map(|s| s.groups()).collect();
+    assert_eq!(groups, vec![vec!["第十七部"], vec!["第十二部"], vec!["第十七部"], vec!["第十一部"]]);
+    assert!(result.segments.iter().all(|s| s.is_match()));
+
+    // “织”、“处”不同韵，押哪一韵分数相同。候选韵按编号排列，取编号在前的第四部，“织”不在此韵
+    let result = match_meter(&rhyme_dict, &text.replace("伤心碧", "伤心处"), &cipai.meter, false);
+    assert_eq!(result.segments[0].groups(), vec!["第四部"]);
+    assert_eq!(result.segments[0].off_rhyme, vec!['织']);
+    assert_eq!(result.tied_rhymes.len(), 1);
+    let tied: Vec<Option<&str>> = result.tied_rhymes[0].0.iter()
+        .map(|(_, r)| r.as_ref().and_then(|r| r.group.as_deref()))
+        .collect();
+    assert_eq!(tied, vec![Some("第十七部"), Some("第十二部"), Some("第十七部"), Some("第十一部")]);
+
+    // 第三韵段与第二韵段同用第十二部，未换韵
+    let text = text.replace("空伫立", "空伫久").replace("归飞急", "归飞后");
+    let result = match_meter(&rhyme_dict, &text, &cipai.meter, false);
+    assert_eq!(result.segments[2].groups(), vec!["第十二部"]);
+    assert!(result.segments[2].same_as_previous);
+    assert!(!result.segments[3].same_as_previous);
+
+    // 韵段交错时按格律中的先后比较：定风波的仄韵段各与平韵段相接，两个仄韵段之间不相接
+    let mut cipai = find_cipai("定风波", "定格");
+    cipai.meter.rhyme_scheme.change_group = true;
+    let scheme = &cipai.meter.rhyme_scheme;
+    let rhyme_of = |c: char, tone: BasicTone| rhyme_dict.get_rhymes_by_char(&c).iter()
+        .find(|r| r.tone == tone).cloned();
+    let (ping, ze, other_ze) = (rhyme_of('来', BasicTone::Ping), rhyme_of('去', BasicTone::Ze),
+                                rhyme_of('月', BasicTone::Ze));
+    let assignment = vec![ping.clone(), ze.clone(), other_ze.clone(), ze.clone()];
+    assert!(scheme.unchanged_segments(&cipai.meter.lines, &assignment).is_empty());
+    // “在”与平韵“来”同在第五部
+    let assignment = vec![ping, rhyme_of('在', BasicTone::Ze), other_ze, ze];
+    assert_eq!(scheme.unchanged_segments(&cipai.meter.lines, &assignment), vec![1]);
+
+    // 不换韵的词牌不列出韵段
+    let cipai = find_cipai("渔歌子", "定格");
+    let result = match_meter(&rhyme_dict, "西塞山前白鹭飞，桃花流水鳜鱼肥。青箬笠，绿蓑衣，斜风细雨不须归。",
+                             &cipai.meter, false);
+    assert!(result.segments.is_empty());
+}
+
+#[test]
+fn test_match_chosen_rhymes() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("西江月", "定格");
+    let text = "明月别枝惊鹊，清风半夜鸣蝉。稻花香里说丰年，听取蛙声一片。\
+                七八个星天外，两三点雨山前。旧时茅店社林边，路转溪桥忽见。";
+    let result = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    let chosen: Vec<(usize, Option<String>)> = result.rhymes.0.iter()
+        .map(|(slot, r)| (*slot, r.as_ref().and_then(|r| r.group.clone())))
+        .collect();
+    assert_eq!(chosen, vec![(0, Some("第七部".to_string())), (1, Some("第七部".to_string()))]);
+    assert!(result.tied_rhymes.is_empty());
+
+    // “行”有第二部、第十一部两读，两种韵脚同分
+    let cipai = find_cipai("十六字令", "定格");
+    let result = match_meter(&rhyme_dict, "行，万里关山一步行。回头望，千山独自行。", &cipai.meter, false);
+    assert_eq!(result.tied_rhymes.len(), 1);
+    let mut groups: Vec<Option<String>> = [&result.rhymes, &result.tied_rhymes[0]].iter()
+        .map(|r| r.0[0].1.as_ref().and_then(|r| r.group.clone()))
+        .collect();
+    groups.sort();
+    assert_eq!(groups, vec![Some("第二部".to_string()), Some("第十一部".to_string())]);
+}
+
+#[test]
+fn test_match_many_tied_rhymes() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("菩萨蛮", "定格");
+    // 每个韵段的两个韵脚都不同部，押哪一部分数都相同，共 16 种同分的韵脚
+    let text = "平林漠漠烟如织，寒山一带伤心处。暝色入高楼，有人楼上天。\
+                玉阶空伫立，宿鸟归飞月。何处是归程，长亭更短家。";
+    let result = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    assert_eq!(result.tied_rhymes.len(), 15);
+    let tied: Vec<(usize, Vec<String>)> = result.tied_slot_rhymes().into_iter()
+        .map(|(slot, rhymes)| (slot, rhymes.iter().map(|r| r.as_ref().unwrap().group.clone().unwrap()).collect()))
+        .collect();
+    assert_eq!(tied, vec![
+        (0, vec!["第四部".to_string(), "第十七部".to_string()]),
+        (1, vec!["第七部".to_string(), "第十二部".to_string()]),
+        (2, vec!["第十七部".to_string(), "第十八部".to_string()]),
+        (3, vec!["第十部".to_string(), "第十一部".to_string()]),
+    ]);
+    // 合并为一行
+    let output = result.to_string();
+    assert_eq!(output.matches("同分韵脚").count(), 1);
+    assert!(output.contains("另有15种"));
+}
+
+#[test]
+fn test_match_unknown_char() {
+    let cipai = find_cipai("十六字令", "定格");
+    let text = "天，快马加鞭未下鞍。惊回首，离天三尺三。";
+    let known = match_meter(&cilin_dict(), text, &cipai.meter, false);
+    assert!(known.unknown_chars.is_empty());
+
+    // 韵书未收录的字单独标出，默认不算错误
+    let text = text.replace('鞍', "𩧢");
+    let result = match_meter(&cilin_dict(), &text, &cipai.meter, false);
+    let line = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.ends_with('𩧢'))).unwrap();
+    assert_eq!(line.match_result.as_ref().unwrap().last(), Some(&MatchType::Unknown));
+    assert_eq!(result.unknown_chars, vec!['𩧢']);
+    assert!((result.score - known.score).abs() < 1e-9);
+
+    // 权重为 0 时与平仄错相同
+    let result = match_meter(&cilin_dict().with_unknown_weight(0.0), &text, &cipai.meter, false);
+    assert!(result.score < known.score);
+}
+
+#[test]
+fn test_match_ambiguous_reading() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("西江月", "定格");
+    let text = "明月别枝惊鹊，清风半夜鸣蝉。稻花香里说丰年，听取蛙声一片。\
+                七八个星天外，两三点雨山前。旧时茅店社林边，路转溪桥忽见。";
+    let result = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    let line = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.starts_with("两三"))).unwrap();
+    // “三”有平、去两读，格律要求平声，首选读音即相合，不标记
+    let match_result = line.match_result.as_ref().unwrap();
+    assert_eq!(match_result[1], MatchType::AllMatch);
+    assert_eq!(line.reading_at(1).unwrap().sheng_diao, ShengDiao::Ping);
+    // 格律可平可仄处不标记
+    let line = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.starts_with("听取"))).unwrap();
+    assert_eq!(line.match_result.as_ref().unwrap()[0], MatchType::AllMatch);
+    assert!(line.reading_at(0).is_some());
+    assert!((result.score - 1.0).abs() < 1e-9);
+
+    // “看”首选平声，格律要求仄声，取仄声读音才相合
+    let result = match_meter(&rhyme_dict, &text.replace('鹊', "看"), &cipai.meter, false);
+    let line = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.starts_with("明月"))).unwrap();
+    assert_eq!(line.match_result.as_ref().unwrap()[5], MatchType::Ambiguous);
+    assert_eq!(line.reading_at(5).unwrap().sheng_diao, ShengDiao::ShangQu);
+    assert!((result.score - 1.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_match_traditional() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("西江月", "定格");
+    let text = "明月別枝驚鵲，清風半夜鳴蟬。稻花香裏說豐年，聽取蛙聲一片。\
+                七八個星天外，兩三點雨山前。舊時茅店社林邊，路轉溪橋忽見。";
+    let result = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    assert!((result.score - 1.0).abs() < 1e-9);
+    assert!(result.unknown_chars.is_empty());
+    // 输出保留原文字形
+    assert!(result.result.iter().any(|r| r.text.as_ref().is_some_and(|t| t.as_str() == "清風半夜鳴蟬")));
+}
+
+#[test]
+fn test_match_variant_char() {
+    let cipai = find_cipai("十六字令", "定格");
+    let known = match_meter(&cilin_dict(), "峰，快马加鞭未下鞍。惊回首，离天三尺三。", &cipai.meter, false);
+    let result = match_meter(&cilin_dict(), "峯，快马加鞭未下鞍。惊回首，离天三尺三。", &cipai.meter, false);
+    assert_eq!(result.variant_chars, vec![('峯', '峰')]);
+    assert!(result.unknown_chars.is_empty());
+    assert!((result.score - known.score).abs() < 1e-9);
+    assert!(result.to_string().contains("异体字：峯→峰"));
+    // 逐字标出折合的字形
+    let line = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.as_str() == "峯")).unwrap();
+    assert_eq!(line.folded_at(0), Some('峰'));
+    assert!(result.result.iter().filter(|r| r.text.as_ref().is_some_and(|t| t.as_str() != "峯")).all(|r| r.folded.is_none()));
+}
+
+#[test]
+fn test_match_web_text() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("十六字令", "定格");
+    let plain = match_meter(&rhyme_dict, "山，快马加鞭未下鞍。惊回首，离天三尺三。", &cipai.meter, false);
+    let input = "山\u{200B}！ 快马加鞭未下鞍……\u{3000}“惊回首”——离天三尺三。";
+    let result = match_meter(&rhyme_dict, input, &cipai.meter, false);
+    assert!((result.score - plain.score).abs() < 1e-9);
+    let line = result.result.iter().find(|r| r.text.as_ref().is_some_and(|t| t.as_str() == "惊回首")).unwrap();
+    let offsets = line.offsets.as_ref().unwrap();
+    assert_eq!(&input[offsets[0]..offsets[2]], "惊回");
+}
+
+#[test]
+fn test_match_title() {
+    let rhyme_dict = cilin_dict();
+    let cipai = find_cipai("十六字令", "定格");
+    let text = "山，快马加鞭未下鞍。惊回首，离天三尺三。";
+    let plain = match_meter(&rhyme_dict, text, &cipai.meter, false);
+    // 标题行不参与匹配，书名号以外的标题须先按词牌名去掉
+    for title in ["十六字令·山\n", "十六字令（其一）\n", "《十六字令》\n"] {
+        let input = format!("{}{}", title, text);
+        let result = match_meter(&rhyme_dict, strip_title(&input, |name| cipai.has_name(name)), &cipai.meter, false);
+        assert!((result.score - plain.score).abs() < 1e-9, "{}", title);
+        assert_eq!(result.result.iter().filter(|r| r.text.is_some()).count(), 4);
+    }
+
+    let content = std::fs::read_to_string("data/cipai/cipai.xml").expect("Should read data file");
+    let cipai_list: Vec<CiPai> = parse_cipai(&content).expect("Should parse successfully")
+        .into_iter()
+        .filter(|c| ["十六字令", "西江月", "渔歌子"].contains(&c.names[0].as_str()))
+        .collect();
+    let results = best_match(&cipai_list, &rhyme_dict, text);
+    assert_eq!(results[0].cipai.names[0], "十六字令");
+    assert!(!results[0].title_match);
+    // 标题中的词牌排在前面，繁体字按简体字比较
+    let results = best_match(&cipai_list, &rhyme_dict, &format!("西江月·山\n{}", text));
+    assert_eq!(results[0].cipai.names[0], "西江月");
+    assert!(results[0].title_match);
+    assert!(cipai_list.iter().any(|c| c.has_name("漁歌子")));
+}
+
+#[test]
+fn test_rank_variants() {
+    let rhyme_dict = cilin_dict();
+    let content = std::fs::read_to_string("data/cipai/cipai.xml").expect("Should read data file");
+    let cipai_list = parse_cipai(&content).expect("Should parse successfully");
+    let variants: Vec<&CiPai> = cipai_list.iter().filter(|c| c.names[0] == "浣溪沙").collect();
+    assert!(variants.len() > 1);
+    let text = "一曲新词酒一杯，去年天气旧亭台。夕阳西下几时回。无可奈何花落去，似曾相识燕归来。小园香径独徘徊。";
+    let results = rank_variants(&variants, &rhyme_dict, text, false);
+    assert_eq!(results.len(), variants.len());
+    let best = &results[0];
+    assert_eq!(best.match_result.score, match_meter(&rhyme_dict, text, &best.cipai.meter, false).score);
+    assert!(results.windows(2).all(|w| w[0].match_result.score >= w[1].match_result.score));
+    assert!(results[1].match_result.score < best.match_result.score);
+}
+
@@ -1,4 +1,14 @@
 pub mod tone;
 pub mod rhyme;
+pub mod charmap;
+pub mod input;
+#[cfg(test)]
+mod input_test;
+#[cfg(test)]
+mod charmap_test;
 pub mod meter;
+pub mod scheme;
+#[cfg(test)]
+mod meter_test;
 pub mod cipai;
+pub mod description;
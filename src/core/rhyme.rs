@@ -1,7 +1,8 @@
-use crate::core::tone::BasicTone;
+use crate::core::charmap::{fanjian, yiti, CharMap};
+use crate::core::tone::{BasicTone, ShengDiao};
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Formatter;
 use std::hash::{Hash, Hasher};
@@ -15,11 +16,17 @@ pub struct Rhyme {
     pub name: String,
     pub group: Option<String>, //韵部，如果为空则不检查韵部
     pub tone: BasicTone,
+    /// 声调，区分上、去、入
+    pub sheng_diao: ShengDiao,
 }
 
 impl fmt::Display for Rhyme {
+    #[allow(clippy::unnecessary_unwrap)]
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{}, {}", self.name, self.tone)?;
+        if self.sheng_diao != ShengDiao::Ping {
+            write!(f, "（{}）", self.sheng_diao)?;
+        }
         if self.group.is_some() {
             write!(f, ", {}", self.group.as_ref().unwrap())?;
         }
@@ -45,6 +52,13 @@ impl Hash for Rhyme {
 pub struct RhymeDict {
     chars_to_rhymes: HashMap<char, Vec<Arc<Rhyme>>>,
     rhyme_to_chars: HashMap<RhymeId, Vec<char>>,
+    sheng_diao: HashSet<ShengDiao>,
+    /// 韵书未收录的字在匹配时计分的权重，1 为不算错误，0 为与平仄错相同
+    unknown_weight: f64,
+    /// 异体字对照表，韵书中查不到异体字时按正字查找
+    variants: CharMap,
+    /// 上去不分的韵书中读去声的字，用于检查去声
+    qu_chars: HashSet<char>,
 }
 
 impl RhymeDict {
@@ -66,7 +80,68 @@ impl RhymeDict {
         let rhyme_to_chars = rhyme_chars
             .into_iter().enumerate().map(|(k, v)| (k as RhymeId, v)).collect();
 
-        Ok(RhymeDict { chars_to_rhymes, rhyme_to_chars})
+        let sheng_diao = rhymes.iter().map(|r| r.sheng_diao).collect();
+
+        Ok(RhymeDict { chars_to_rhymes, rhyme_to_chars, sheng_diao, unknown_weight: 1.0, variants: yiti().clone(),
+            qu_chars: HashSet::new() })
+    }
+
+    /// 设置未收录的字在匹配时计分的权重
+    pub fn with_unknown_weight(mut self, weight: f64) -> RhymeDict {
+        self.unknown_weight = weight;
+        self
+    }
+
+    pub fn unknown_weight(&self) -> f64 {
+        self.unknown_weight
+    }
+
+    /// 补充异体字对照表，与内置对照表冲突时以补充的为准
+    pub fn with_variants(mut self, variants: CharMap) -> RhymeDict {
+        self.variants.extend(variants);
+        self
+    }
+
+    /// 补充去声字，上去不分的韵书借此检查去声
+    pub fn with_qu_chars(mut self, chars: impl IntoIterator<Item = char>) -> RhymeDict {
+        self.qu_chars.extend(chars);
+        self
+    }
+
+    /// 韵书中有该声调读音的字
+    pub fn chars_by_sheng_diao(&self, sheng_diao: ShengDiao) -> HashSet<char> {
+        self.chars_to_rhymes.iter()
+            .filter(|(_, rhymes)| rhymes.iter().any(|r| r.sheng_diao == sheng_diao))
+            .map(|(c, _)| *c)
+            .collect()
+    }
+
+    /// 韵书收录的字形：韵书收录的字为原字，未收录的繁体字按简体字查找
+    fn fold_char(&self, c: &char) -> Option<char> {
+        if self.chars_to_rhymes.contains_key(c) {
+            return Some(*c);
+        }
+        fanjian().get(c).filter(|s| self.chars_to_rhymes.contains_key(s))
+    }
+
+    /// 异体字所对应的韵书字形，韵书收录该字或对照表中没有该字时为 None
+    pub fn variant_of(&self, c: &char) -> Option<char> {
+        if self.fold_char(c).is_some() {
+            return None;
+        }
+        self.variants.get(c).and_then(|v| self.fold_char(&v))
+    }
+
+    /// 查韵书时所用的字形：依次按原字、简体字、异体字的正字查找，都找不到时仍为原字
+    pub fn dict_char(&self, c: &char) -> char {
+        self.fold_char(c)
+            .or_else(|| self.variant_of(c))
+            .unwrap_or(*c)
+    }
+
+    /// 韵书是否收录了该字
+    pub fn contains_char(&self, c: &char) -> bool {
+        self.chars_to_rhymes.contains_key(&self.dict_char(c))
     }
 
     pub fn get_chars_by_rhyme(&self, id: &RhymeId) -> &[char] {
@@ -76,9 +151,25 @@ impl RhymeDict {
             .unwrap_or(&[])
     }
 
+    /// 韵书是否单列入声韵部
+    pub fn has_ru_sheng(&self) -> bool {
+        self.sheng_diao.contains(&ShengDiao::Ru)
+    }
+
+    /// 韵书是否区分去声，如平水韵，或补充了去声字
+    pub fn has_qu_sheng(&self) -> bool {
+        self.sheng_diao.contains(&ShengDiao::Qu) || !self.qu_chars.is_empty()
+    }
+
+    /// 字是否有去声读音
+    pub fn is_qu_sheng(&self, c: &char) -> bool {
+        self.get_rhymes_by_char(c).iter().any(|r| r.sheng_diao == ShengDiao::Qu)
+            || self.qu_chars.contains(c) || self.qu_chars.contains(&self.dict_char(c))
+    }
+
     pub fn get_rhymes_by_char(&self, c: &char) -> &[Arc<Rhyme>] {
         self.chars_to_rhymes
-            .get(c)
+            .get(&self.dict_char(c))
             .map(|v| v.as_slice())
             .unwrap_or(&[])
     }
@@ -0,0 +1,225 @@
+use std::fmt::{Display, Formatter};
+use colored::Colorize;
+use colored::control::SHOULD_COLORIZE;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use crate::core::rhyme::Rhyme;
+use crate::core::tone::{get_contrasting_color, BasicTone, MeterTone};
+
+/// 韵位：格律中用同一韵脚符号标记的所有韵脚，须押同一韵
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RhymeSlot {
+    /// 格律中的韵脚符号，如％、＊、a
+    pub marker: char,
+    pub tone: BasicTone,
+    /// 所在韵段，从 0 开始。换韵则韵段不同，须同部的韵位属同一韵段
+    pub segment: usize,
+}
+
+/// 平韵与仄韵须同部的格式
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PingZeLink {
+    /// 平仄韵通叶格
+    TongYe,
+    /// 平仄韵错叶格，说明中写明同部
+    CuoYe,
+}
+
+/// 用韵方式：各韵位及韵位之间的约束。韵位的下标即 MeterTone 中的 rhyme
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RhymeScheme {
+    pub slots: Vec<RhymeSlot>,
+    /// 须用同一韵部的韵位，如通叶的平韵与仄韵
+    pub same_group: Vec<(usize, usize)>,
+    /// 换韵时须换用其他韵部，如平仄韵转换格
+    #[serde(default)]
+    pub change_group: bool,
+    /// 平韵与仄韵须同部时的格式
+    #[serde(default)]
+    pub ping_ze_link: Option<PingZeLink>,
+}
+
+impl RhymeScheme {
+    /// 韵脚符号所属的韵位，首次出现时新建韵位。同一字母既标平韵又标仄韵时，平仄同部
+    pub fn slot(&mut self, marker: char, tone: BasicTone) -> usize {
+        if let Some(i) = self.slots.iter().position(|s| s.marker == marker && s.tone == tone) {
+            return i;
+        }
+        let idx = self.slots.len();
+        self.slots.push(RhymeSlot { marker, tone, segment: 0 });
+        if let Some(other) = self.slots.iter().position(|s| s.marker == marker && s.tone != self.slots[idx].tone) {
+            self.same_group.push((other, idx));
+        }
+        self.update_segments();
+        idx
+    }
+
+    /// 平仄韵通叶或同部错叶：平韵与仄韵须同部
+    pub fn link_ping_ze(&mut self, link: PingZeLink) {
+        self.ping_ze_link = Some(link);
+        for (ze, slot) in self.slots.iter().enumerate() {
+            if slot.tone != BasicTone::Ze {
+                continue;
+            }
+            for (ping, other) in self.slots.iter().enumerate() {
+                if other.tone == BasicTone::Ping && !self.linked_slots(ping).contains(&ze) {
+                    self.same_group.push((ping.min(ze), ping.max(ze)));
+                }
+            }
+        }
+        self.same_group.sort();
+        self.update_segments();
+    }
+
+    /// 按首次出现的顺序给韵段编号，须同部的韵位属同一韵段
+    fn update_segments(&mut self) {
+        let mut segments: Vec<Option<usize>> = vec![None; self.slots.len()];
+        let mut count = 0;
+        for i in 0..self.slots.len() {
+            if segments[i].is_some() {
+                continue;
+            }
+            let mut stack = vec![i];
+            while let Some(j) = stack.pop() {
+                if segments[j].is_none() {
+                    segments[j] = Some(count);
+                    stack.extend(self.linked_slots(j));
+                }
+            }
+            count += 1;
+        }
+        for (slot, segment) in self.slots.iter_mut().zip(segments) {
+            slot.segment = segment.unwrap();
+        }
+    }
+
+    /// 韵段包含的韵位
+    pub fn segment_slots(&self, segment: usize) -> Vec<usize> {
+        (0..self.slots.len()).filter(|&i| self.slots[i].segment == segment).collect()
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.slots.iter().map(|s| s.segment + 1).max().unwrap_or(0)
+    }
+
+    /// 韵脚分配中须同部而所押韵部不同的韵位对。韵书不分韵部时不检查
+    pub fn group_mismatches(&self, assignment: &[Option<Arc<Rhyme>>]) -> Vec<(usize, usize)> {
+        self.same_group.iter()
+            .filter(|&&(a, b)| match (&assignment[a], &assignment[b]) {
+                (Some(ra), Some(rb)) => ra.group.is_some() && ra.group != rb.group,
+                _ => false,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// 格律中前后相接的两个韵段，按韵脚在格律中的先后，先出现的韵段在前。
+    /// 韵段交错时（如定风波）同一对只列一次
+    pub fn adjacent_segments<L: AsRef<[MeterTone]>>(&self, meter: &[L]) -> Vec<(usize, usize)> {
+        let mut pairs = vec![];
+        let mut previous: Option<usize> = None;
+        for slot in meter.iter().flat_map(|line| line.as_ref().iter().filter_map(|t| t.rhyme)) {
+            let segment = self.slots[slot].segment;
+            if let Some(p) = previous && p != segment {
+                let pair = (p.min(segment), p.max(segment));
+                if !pairs.contains(&pair) {
+                    pairs.push(pair);
+                }
+            }
+            previous = Some(segment);
+        }
+        pairs
+    }
+
+    /// 须换部时，韵脚分配中与相接的韵段用同一韵部的韵段，取每对中后出现的一个。韵书不分韵部时不检查
+    pub fn unchanged_segments<L: AsRef<[MeterTone]>>(&self, meter: &[L], assignment: &[Option<Arc<Rhyme>>]) -> Vec<usize> {
+        if !self.change_group {
+            return vec![];
+        }
+        let groups = |segment: usize| -> Vec<&String> {
+            self.segment_slots(segment).into_iter()
+                .filter_map(|slot| assignment[slot].as_ref()?.group.as_ref())
+                .collect()
+        };
+        let mut segments: Vec<usize> = self.adjacent_segments(meter).into_iter()
+            .filter(|&(a, b)| groups(b).iter().any(|g| groups(a).contains(g)))
+            .map(|(_, b)| b)
+            .collect();
+        segments.sort();
+        segments.dedup();
+        segments
+    }
+
+    /// 给韵位分配韵时，是否与已分配的韵冲突：须同部的韵位不同部，或须换部而与相接的韵段同部
+    pub fn conflicts(&self, slot: usize, rhyme: &Rhyme, adjacent: &[(usize, usize)],
+                     assignment: &[Option<Arc<Rhyme>>]) -> bool {
+        let Some(group) = &rhyme.group else { return false };
+        let group_of = |other: usize| assignment[other].as_ref().and_then(|r| r.group.as_ref());
+        if self.linked_slots(slot).into_iter().any(|other| group_of(other).is_some_and(|g| g != group)) {
+            return true;
+        }
+        if !self.change_group {
+            return false;
+        }
+        let segment = self.slots[slot].segment;
+        adjacent.iter()
+            .filter_map(|&(a, b)| if a == segment { Some(b) } else if b == segment { Some(a) } else { None })
+            .flat_map(|other| self.segment_slots(other))
+            .any(|other| group_of(other) == Some(group))
+    }
+
+    /// 两韵位须同部的缘由：同一字母既标平韵又标仄韵，或平仄韵通叶、错叶
+    pub fn link_name(&self, a: usize, b: usize) -> &'static str {
+        if self.slots[a].marker == self.slots[b].marker {
+            return "同字母平仄韵";
+        }
+        match self.ping_ze_link {
+            Some(PingZeLink::TongYe) => "通叶",
+            Some(PingZeLink::CuoYe) => "错叶",
+            None => "须同部的韵位",
+        }
+    }
+
+    /// 与该韵位须同部的其他韵位
+    pub fn linked_slots(&self, slot: usize) -> Vec<usize> {
+        self.same_group.iter()
+            .filter_map(|&(a, b)| if a == slot { Some(b) } else if b == slot { Some(a) } else { None })
+            .collect()
+    }
+}
+
+/// 韵位名称，彩色显示时与格律中的韵脚同色
+pub fn slot_name(slot: usize) -> String {
+    let name = format!("韵{}", slot);
+    if SHOULD_COLORIZE.should_colorize() {
+        let color = get_contrasting_color(slot);
+        name.truecolor(color.0, color.1, color.2).to_string()
+    } else {
+        name
+    }
+}
+
+impl Display for RhymeScheme {
+    /// 如“韵0为平韵；韵1为仄韵，与韵0同部；韵2为仄韵，换韵”
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.slots.is_empty() {
+            return write!(f, "无韵");
+        }
+        for (i, slot) in self.slots.iter().enumerate() {
+            if i > 0 {
+                write!(f, "；")?;
+            }
+            write!(f, "{}为{}韵", slot_name(i), slot.tone)?;
+            let linked: Vec<String> = self.linked_slots(i).into_iter()
+                .filter(|&j| j < i)
+                .map(slot_name)
+                .collect();
+            if !linked.is_empty() {
+                write!(f, "，与{}同部", linked.join("、"))?;
+            } else if slot.segment > 0 {
+                write!(f, "，换韵")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -6,6 +6,7 @@ use colored::Colorize;
 use serde::{Deserialize, Serialize};
 use colored::control::SHOULD_COLORIZE;
 use palette::{FromColor, Hsl, Srgb};
+use crate::core::scheme::slot_name;
 
 #[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[derive(Debug)]
@@ -22,44 +23,128 @@ impl fmt::Display for BasicTone {
     }
 }
 
+/// 声调，比平仄更细。韵书不分上去时为 ShangQu
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum ShengDiao {
+    Ping, // 平声
+    Shang, // 上声
+    Qu, // 去声
+    Ru, // 入声
+    ShangQu, // 上声或去声
+}
+
+impl ShengDiao {
+    pub fn basic(&self) -> BasicTone {
+        match self {
+            ShengDiao::Ping => BasicTone::Ping,
+            _ => BasicTone::Ze,
+        }
+    }
+}
+
+impl fmt::Display for ShengDiao {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ShengDiao::Ping => write!(f, "平声"),
+            ShengDiao::Shang => write!(f, "上声"),
+            ShengDiao::Qu => write!(f, "去声"),
+            ShengDiao::Ru => write!(f, "入声"),
+            ShengDiao::ShangQu => write!(f, "上去声"),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum MeterToneType {
     Ping, // 平声
     Ze, // 仄声
     Zhong, // 平声仄声皆可
+    Qu, // 仄声中须用去声
+}
+
+impl MeterToneType {
+    /// 对应的平仄，中为 None
+    pub fn basic(&self) -> Option<BasicTone> {
+        match self {
+            MeterToneType::Ping => Some(BasicTone::Ping),
+            MeterToneType::Ze | MeterToneType::Qu => Some(BasicTone::Ze),
+            MeterToneType::Zhong => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize)]
+pub enum MeterToneKind {
+    #[default]
+    Normal,
+    Lingzi, // 领格字
+    Chenzi, // 衬字，可填可不填，平仄不限
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub struct MeterTone {
     pub tone: MeterToneType,
-    pub rhyme_num: Option<i32>,
+    /// 韵脚所在韵位在 RhymeScheme 中的下标，非韵脚为 None
+    pub rhyme: Option<usize>,
+    #[serde(default)]
+    pub optional: bool, // 可选可省略
+    /// 所在可省略段的编号，同一段的字一并省略或保留。不在可省略段中的为 None，衬字单独省略
+    #[serde(default)]
+    pub group: Option<usize>,
+    #[serde(default)]
+    pub kind: MeterToneKind,
+    #[serde(default)]
+    pub dou: bool, // 此字后有豆，即句中停顿
+}
+
+impl MeterTone {
+    /// 不押韵、不可省略的普通字
+    pub fn new(tone: MeterToneType) -> MeterTone {
+        MeterTone { tone, rhyme: None, optional: false, group: None, kind: MeterToneKind::Normal, dou: false }
+    }
 }
 
 impl fmt::Display for MeterTone {
 
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        let tone_str = match self.tone {
-            MeterToneType::Ping => "平",
-            MeterToneType::Ze => "仄",
-            MeterToneType::Zhong => "中",
+        let tone_str = match (&self.kind, &self.tone) {
+            (MeterToneKind::Chenzi, _) => "衬",
+            (MeterToneKind::Lingzi, MeterToneType::Ping) => "领平",
+            (MeterToneKind::Lingzi, MeterToneType::Ze) => "领仄",
+            (_, MeterToneType::Ping) => "平",
+            (_, MeterToneType::Ze) => "仄",
+            (_, MeterToneType::Zhong) => "中",
+            (_, MeterToneType::Qu) => "去",
         };
 
         if SHOULD_COLORIZE.should_colorize() {
-            let colored_tone = match self.rhyme_num {
+            let colored_tone = match self.rhyme {
                 Some(n) => {
-                    let color = get_contrasting_color(n as usize);
+                    let color = get_contrasting_color(n);
                     tone_str.truecolor(color.0, color.1, color.2)
                 }
                 _ => tone_str.normal(),
             };
-            write!(f, "{}", colored_tone)
+            if self.optional {
+                write!(f, "〔{}〕", colored_tone)?;
+            } else {
+                write!(f, "{}", colored_tone)?;
+            }
         } else {
-            let anno_tone = match self.rhyme_num {
+            let anno_tone = match self.rhyme {
                 Some(num) => format!("{}（韵{}）", tone_str, num),
                 _ => tone_str.to_string(),
             };
-            write!(f, "{}", anno_tone)
+            if self.optional {
+                write!(f, "〔{}〕", anno_tone)?;
+            } else {
+                write!(f, "{}", anno_tone)?;
+            }
+        }
+        if self.dou {
+            write!(f, "、")?;
         }
+        Ok(())
     }
 }
 
@@ -85,10 +170,9 @@ pub fn get_contrasting_color(n: usize) -> (u8, u8, u8) {
 
 /// Will return error if pass in ToneType::Zhong since it can map to either Ping or Ze
 pub fn get_basic_tone(t: &MeterTone) -> Result<BasicTone> {
-    match t.tone {
-        MeterToneType::Ping  => Ok(BasicTone::Ping),
-        MeterToneType::Ze => Ok(BasicTone::Ze),
-        _ => bail!("Cannot map to basic tone for type {:?}", t)
+    match t.tone.basic() {
+        Some(tone) => Ok(tone),
+        None => bail!("Cannot map to basic tone for type {:?}", t)
     }
 }
 
@@ -100,24 +184,19 @@ pub fn tone_match(t1: &BasicTone, t2: &MeterTone) -> bool {
     t1 == &get_basic_tone(t2).unwrap()
 }
 
-/// 获取格律颜色说明
-pub fn get_tone_legend(max_rhyme_num: i32) -> String {
+/// 获取格律颜色说明，slot_count 为用韵方式中韵位的数量
+pub fn get_tone_legend(slot_count: usize) -> String {
     if SHOULD_COLORIZE.should_colorize() {
-        let mut legend = "格律说明：平=平声 仄=仄声 中=平仄皆可".to_string();
+        let mut legend = "格律说明：平=平声 仄=仄声 去=须用去声 中=平仄皆可 〔〕=可省略 领平/领仄=领格字 衬=衬字".to_string();
 
-        if max_rhyme_num >= 0 {
+        if slot_count > 0 {
             legend.push_str("。韵脚使用不同颜色表示：");
-            let rhyme_parts: Vec<String> = (0..=max_rhyme_num)
-                .map(|n| {
-                    let color = get_contrasting_color(n as usize);
-                    format!("{}", format!("韵{}", n).truecolor(color.0, color.1, color.2))
-                })
-                .collect();
+            let rhyme_parts: Vec<String> = (0..slot_count).map(slot_name).collect();
             legend.push_str(&rhyme_parts.join("，"));
         }
 
         legend
     } else {
-        "格律说明：如是韵脚，括号内标注声部".to_string()
+        "格律说明：如是韵脚，括号内标注声部。〔〕内为可省略的字，〔省〕表示已省略。去=须用去声，领平/领仄=领格字，衬=衬字".to_string()
     }
 }
@@ -1,18 +1,19 @@
 
-mod core;
-mod parser;
+pub mod core;
+pub mod parser;
 
-use std::sync::Arc;
-use anyhow::{bail, Result};
+use std::path::PathBuf;
+use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand, ValueEnum};
 use clap_web_macro::{web_ui_bind, wprint, wprintln};
 use parser::rhyme_parser::parse_pingshui;
-use parser::cipai_parser::parse_cipai;
-use crate::core::cipai::best_match;
-use crate::core::meter::{get_match_legend, match_meter};
+use parser::cipai_parser::{load_cipai_file_with_diagnostics, parse_cipai_with_diagnostics, CiPaiParseResult};
+use crate::core::cipai::{best_match, merge_cipai, rank_variants, CiPai, CiPaiCategory};
+use crate::core::meter::{get_match_legend, match_meter, match_stanza, stanza_name};
+use crate::core::input::{parse_title, strip_title};
 use crate::core::rhyme::RhymeDict;
-use crate::core::tone::{MeterTone, get_tone_legend};
-use crate::parser::rhyme_parser::parse_cilin;
+use crate::core::tone::{get_tone_legend, ShengDiao};
+use crate::parser::rhyme_parser::{load_rhyme_dict_file, load_yiti_file, parse_cilin, RhymeDictFormat};
 
 // Embed data files at compile time
 const PINGSHUI_RHYME_DATA: &str = include_str!("../data/rhyme/Pingshui_Rhyme.json");
@@ -30,6 +31,49 @@ enum DictType {
     Xinyun,
 }
 
+#[derive(Debug, Clone, ValueEnum)]
+enum DictFormat {
+    /// 声部 -> 韵目 -> 字，同平水韵文件
+    Pingshui,
+    /// 韵部 -> 声调 -> 字，同词林正韵、中华新韵文件
+    Cilin,
+}
+
+impl From<&DictFormat> for RhymeDictFormat {
+    fn from(format: &DictFormat) -> Self {
+        match format {
+            DictFormat::Pingshui => RhymeDictFormat::Pingshui,
+            DictFormat::Cilin => RhymeDictFormat::Cilin,
+        }
+    }
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+enum Category {
+    /// 平韵格
+    Ping,
+    /// 仄韵格
+    Ze,
+    /// 平仄韵转换格
+    Zhuanhuan,
+    /// 平仄韵通叶格
+    Tongye,
+    /// 平仄韵错叶格
+    Cuoye,
+}
+
+impl From<&Category> for CiPaiCategory {
+    fn from(category: &Category) -> Self {
+        match category {
+            Category::Ping => CiPaiCategory::PingYun,
+            Category::Ze => CiPaiCategory::ZeYun,
+            Category::Zhuanhuan => CiPaiCategory::ZhuanHuan,
+            Category::Tongye => CiPaiCategory::TongYe,
+            Category::Cuoye => CiPaiCategory::CuoYe,
+        }
+    }
+}
+
 #[derive(Parser)]
 #[command(name = "rhyme-checker")]
 #[command(about = "诗词格律检查工具", long_about = None)]
@@ -38,6 +82,30 @@ pub struct Cli {
     #[arg(short = 't', long, value_enum, default_value = "cilin")]
     dict_type: DictType,
 
+    /// 自定义韵书 JSON 文件，指定后忽略韵书类型
+    #[arg(long, value_name = "PATH")]
+    dict_file: Option<PathBuf>,
+
+    /// 自定义韵书文件格式，可选，如为空则自动识别
+    #[arg(long, value_enum, requires = "dict_file")]
+    dict_format: Option<DictFormat>,
+
+    /// 自定义词牌 XML 文件，格式同内置词牌数据，可多次指定。同名同变体的词牌覆盖内置词牌
+    #[arg(long, value_name = "PATH")]
+    cipai_file: Vec<PathBuf>,
+
+    /// 自定义异体字对照表，每行一对字，前为异体字，后为正字，可多次指定。补充内置的对照表
+    #[arg(long, value_name = "PATH")]
+    yiti_file: Vec<PathBuf>,
+
+    /// 韵书未收录的字在匹配时计分的权重，1 为不算错误，0 为与平仄错相同
+    #[arg(long, value_name = "WEIGHT", default_value = "1.0", value_parser = parse_unknown_weight)]
+    unknown_weight: f64,
+
+    /// 严格模式：词牌格律有任何解析错误即退出，而不是忽略出错的格律
+    #[arg(long)]
+    strict: bool,
+
     /// 输出不用颜色区分格律和结果
     #[arg(long)]
     no_color: bool,
@@ -72,13 +140,17 @@ enum Commands {
 
     /// 检查格律
     MatchCiPai {
-        /// 词牌名
+        /// 词牌名，可选，如为空则按文字的标题行（如“定风波·题目”、“《定风波》”）选择词牌
         #[arg(short, long)]
-        ci_pai: String,
+        ci_pai: Option<String>,
 
-        /// 格律变种，如定格、格一等
+        /// 格律变种，如定格、格一等，可选，如为空则与此词牌所有格律变种匹配，显示最相合的变种
         #[arg(short, long)]
-        variant: String,
+        variant: Option<String>,
+
+        /// 只填了一片时，将文本分别与每一片匹配
+        #[arg(long)]
+        single_stanza: bool,
 
         #[arg(value_name = "TEXT")]
         text: String,
@@ -90,9 +162,31 @@ enum Commands {
         #[arg(short = 'n', long, default_value = "5")]
         top: usize,
 
+        /// 只在此类别的词牌中查找
+        #[arg(long, value_enum)]
+        category: Option<Category>,
+
         #[arg(value_name = "TEXT")]
         text: String,
+    },
+
+    /// 列出词牌及其格律变种
+    ListCiPai {
+        /// 只列出此类别的词牌
+        #[arg(long, value_enum)]
+        category: Option<Category>,
+    },
+
+    /// 检查内置及自定义词牌数据，列出解析错误，以及说明中的字数、韵数与格律不符之处
+    ValidateData,
+}
+
+fn parse_unknown_weight(s: &str) -> Result<f64> {
+    let weight: f64 = s.parse()?;
+    if !(0.0..=1.0).contains(&weight) {
+        bail!("权重须在 0 到 1 之间");
     }
+    Ok(weight)
 }
 
 fn query_char_rhyme(rhyme_dict: &RhymeDict, character: &str, show_all: bool) -> Result<()> {
@@ -111,6 +205,16 @@ fn query_char_rhyme(rhyme_dict: &RhymeDict, character: &str, show_all: bool) ->
         return Ok(());
     }
 
+    if let Some(v) = rhyme_dict.variant_of(&query_char) {
+        wprintln!("异体字: {}→{}", query_char, v);
+    } else if rhyme_dict.dict_char(&query_char) != query_char {
+        wprintln!("按简体字查询: {}→{}", query_char, rhyme_dict.dict_char(&query_char));
+    }
+
+    if rhymes.iter().any(|r| r.tone != rhymes[0].tone) {
+        wprintln!("多音字: 有平仄两读，检查格律时取与格律相合的读音");
+    }
+
     for rhyme in rhymes {
         wprintln!("韵部: {}", rhyme);
 
@@ -136,9 +240,74 @@ fn query_char_rhyme(rhyme_dict: &RhymeDict, character: &str, show_all: bool) ->
     Ok(())
 }
 
-fn query_cipai(name: &str, variant: Option<&String>) -> Result<()> {
-    let cipai_list = parse_cipai(CIPAI_DATA)?;
+/// 加载内置及自定义词牌。非严格模式下忽略解析出错的格律，并提示出错的数量
+fn load_cipai_list(cipai_files: &[PathBuf], strict: bool) -> Result<Vec<CiPai>> {
+    let mut cipai_list = vec![];
+    let mut error_count = 0;
+    for (i, result) in parse_cipai_sources(cipai_files)?.into_iter().enumerate() {
+        let cipai = if strict {
+            result.into_strict()?
+        } else {
+            error_count += result.diagnostics.len();
+            result.cipai
+        };
+        cipai_list = if i == 0 { cipai } else { merge_cipai(cipai_list, cipai) };
+    }
+    // 网页版只显示 wprintln! 的输出，提示与结果同一输出，并以空行隔开
+    if error_count > 0 {
+        wprintln!("注意：{}个词牌格律解析失败，已忽略。可用 validate-data 查看详情\n", error_count);
+    }
+    Ok(cipai_list)
+}
 
+/// 解析内置词牌及各个自定义词牌文件
+fn parse_cipai_sources(cipai_files: &[PathBuf]) -> Result<Vec<CiPaiParseResult>> {
+    let mut results = vec![parse_cipai_with_diagnostics(CIPAI_DATA)?];
+    for path in cipai_files {
+        results.push(load_cipai_file_with_diagnostics(path)?);
+    }
+    Ok(results)
+}
+
+fn validate_data(cipai_files: &[PathBuf]) -> Result<()> {
+    let sources: Vec<String> = std::iter::once("内置词牌".to_string())
+        .chain(cipai_files.iter().map(|p| p.display().to_string()))
+        .collect();
+    let mut error_count = 0;
+    for (source, result) in sources.iter().zip(parse_cipai_sources(cipai_files)?) {
+        let mismatches: Vec<String> = result.cipai.iter()
+            .flat_map(|c| {
+                let name = match &c.variant {
+                    Some(variant) => format!("{}（{}）", c.names[0], variant),
+                    None => c.names[0].clone(),
+                };
+                c.count_mismatches().into_iter().map(move |m| format!("{}：{}", name, m))
+            })
+            .collect();
+        wprintln!("{}：{}个格律，{}个解析错误，{}处标记有误已忽略，{}处字数、韵数与说明不符，{}处说明未能对应到格律",
+            source, result.cipai.len() + result.diagnostics.len(), result.diagnostics.len(), result.warnings.len(),
+            mismatches.len(), result.unattributed_counts.len());
+        for d in &result.diagnostics {
+            wprintln!("  错误：{}", d);
+        }
+        for w in &result.warnings {
+            wprintln!("  警告：{}", w);
+        }
+        for m in &mismatches {
+            wprintln!("  提示：{}", m);
+        }
+        for (name, stated) in &result.unattributed_counts {
+            wprintln!("  提示：{}：说明中{}未能对应到格律", name, stated);
+        }
+        error_count += result.diagnostics.len();
+    }
+    if error_count > 0 {
+        bail!("词牌数据有{}个解析错误", error_count);
+    }
+    Ok(())
+}
+
+fn query_cipai(cipai_list: &[CiPai], name: &str, variant: Option<&String>) -> Result<()> {
     let matching_cipai: Vec<_> = cipai_list
         .iter()
         .filter(|cipai| {
@@ -153,13 +322,13 @@ fn query_cipai(name: &str, variant: Option<&String>) -> Result<()> {
         return Ok(());
     }
 
-    let max_rhyme_num = matching_cipai
+    let slot_count = matching_cipai
         .iter()
-        .map(|cipai| cipai.get_max_rhyme_num())
+        .map(|cipai| cipai.rhyme_slot_count())
         .max()
         .unwrap_or(0);
 
-    wprintln!("{}\n", get_tone_legend(max_rhyme_num));
+    wprintln!("{}\n", get_tone_legend(slot_count));
 
     for (i, cipai) in matching_cipai.iter().enumerate() {
         if i > 0 {
@@ -172,47 +341,110 @@ fn query_cipai(name: &str, variant: Option<&String>) -> Result<()> {
     Ok(())
 }
 
-fn match_cipai(rhyme_dict: &RhymeDict, name: &str, variant: &str, text: &str) -> Result<()> {
+fn match_cipai(rhyme_dict: &RhymeDict, cipai_list: &[CiPai], name: Option<&str>, variant: Option<&str>,
+               single_stanza: bool, text: &str) -> Result<()> {
+    let (name, from_title) = match name {
+        Some(name) => (name.to_string(), false),
+        None => {
+            let title = parse_title(text).context("未指定词牌，且文字开头没有标题行，如“定风波·题目”")?;
+            wprintln!("按标题选择词牌：{}", title.name);
+            (title.name, true)
+        }
+    };
+    let text = strip_title(text, |name| cipai_list.iter().any(|c| c.has_name(name)));
+    let name_match = |cipai: &CiPai| if from_title {
+        cipai.has_name(&name)
+    } else {
+        cipai.names.iter().any(|n| n.contains(name.as_str()))
+    };
 
-    let cipai_list = parse_cipai(CIPAI_DATA)?;
-    let cipai= cipai_list
-        .iter()
-        .find(|cipai| cipai.names.iter().any(|n| n.contains(name))
-            && cipai.variant.is_some()
-            && cipai.variant.as_ref().unwrap() == variant
-        );
-    if cipai.is_none() {
-        bail!("未找到词牌: {}, {}", name, variant);
+    let mut ranked = vec![];
+    let cipai = match variant {
+        Some(variant) => cipai_list
+            .iter()
+            .find(|cipai| name_match(cipai) && cipai.variant.as_deref() == Some(variant))
+            .with_context(|| format!("未找到词牌: {}, {}", name, variant))?,
+        None => {
+            let first = cipai_list.iter().find(|cipai| name_match(cipai))
+                .with_context(|| format!("未找到词牌: {}", name))?;
+            let variants: Vec<&CiPai> = cipai_list.iter().filter(|c| first.is_same_cipai(c)).collect();
+            ranked = rank_variants(&variants, rhyme_dict, text, single_stanza);
+            ranked[0].cipai
+        }
+    };
+    if variant.is_none() {
+        wprintln!("最佳变体：{}\n", cipai.variant.as_deref().unwrap_or("无"));
     }
-    let cipai = cipai.unwrap();
-    let meter_vec: Vec<Arc<[MeterTone]>> = cipai.meter.iter()
-        .cloned()
-        .map(Into::into)
-        .collect();
-    let max_rhyme_num = cipai.get_max_rhyme_num();
-    wprintln!("{}", get_tone_legend(max_rhyme_num));
+    let slot_count = cipai.rhyme_slot_count();
+    wprintln!("{}", get_tone_legend(slot_count));
     wprintln!("{}\n", get_match_legend());
-    let result = match_meter(rhyme_dict, text, &meter_vec, false);
-    wprintln!("{}", result);
+    if single_stanza {
+        let stanza_count = cipai.meter.stanza_starts.len();
+        for (i, result) in match_stanza(rhyme_dict, text, &cipai.meter) {
+            let name = stanza_name(i, stanza_count).unwrap_or("全词".to_string());
+            wprintln!("{}\n{}", name, result);
+        }
+    } else if let Some(best) = ranked.first() {
+        wprintln!("{}", best.match_result);
+    } else {
+        wprintln!("{}", match_meter(rhyme_dict, text, &cipai.meter, false));
+    }
+    if ranked.len() > 1 {
+        wprintln!("各变体匹配分数：");
+        for (i, r) in ranked.iter().enumerate() {
+            wprintln!("{}. {}：{:.2}", i + 1, r.cipai.variant.as_deref().unwrap_or("无"), r.match_result.score);
+        }
+    }
     Ok(())
 }
 
-fn best_match_cipai(rhyme_dict: &RhymeDict, top: usize, text: &str) -> Result<()> {
-    let cipai_list = parse_cipai(CIPAI_DATA)?;
+fn filter_category(cipai_list: Vec<CiPai>, category: Option<&Category>) -> Vec<CiPai> {
+    match category {
+        None => cipai_list,
+        Some(category) => {
+            let category = CiPaiCategory::from(category);
+            cipai_list.into_iter().filter(|c| c.category == Some(category)).collect()
+        }
+    }
+}
 
+fn list_cipai(cipai_list: &[CiPai]) -> Result<()> {
     if cipai_list.is_empty() {
         bail!("未找到任何词牌");
     }
+    // 同一词牌的变体相邻，合并为一行
+    let mut i = 0;
+    while i < cipai_list.len() {
+        let cipai = &cipai_list[i];
+        let variants: Vec<String> = cipai_list[i..].iter()
+            .take_while(|c| cipai.is_same_cipai(c))
+            .map(|c| match (&c.variant, &c.category) {
+                (Some(variant), Some(category)) => format!("{}（{}）", variant, category),
+                (Some(variant), None) => variant.clone(),
+                (None, Some(category)) => format!("（{}）", category),
+                (None, None) => String::new(),
+            })
+            .collect();
+        i += variants.len();
+        wprintln!("{}：{}", cipai.names[0], variants.join("、"));
+    }
+    Ok(())
+}
 
-    let results = best_match(&cipai_list, rhyme_dict, text);
+fn best_match_cipai(rhyme_dict: &RhymeDict, cipai_list: &[CiPai], top: usize, text: &str) -> Result<()> {
+    if cipai_list.is_empty() {
+        bail!("未找到任何词牌");
+    }
 
-    let max_rhyme_num = cipai_list
+    let results = best_match(cipai_list, rhyme_dict, text);
+
+    let slot_count = cipai_list
         .iter()
-        .map(|cipai| cipai.get_max_rhyme_num())
+        .map(|cipai| cipai.rhyme_slot_count())
         .max()
         .unwrap_or(0);
 
-    wprintln!("{}", get_tone_legend(max_rhyme_num));
+    wprintln!("{}", get_tone_legend(slot_count));
     wprintln!("{}\n", get_match_legend());
 
     let display_count = top.min(results.len());
@@ -237,21 +469,35 @@ pub fn run(cli: &Cli) -> Result<()> {
         colored::control::set_override(true);
     }
 
-    let rhyme_dict = match cli.dict_type {
-        DictType::Pingshui => parse_pingshui(PINGSHUI_RHYME_DATA)?,
-        DictType::Cilin => parse_cilin(CILIN_RHYME_DATA)?,
-        DictType::Xinyun => parse_cilin(XINYUN_RHYME_DATA)?,
-    };
+    let mut rhyme_dict = match &cli.dict_file {
+        Some(path) => load_rhyme_dict_file(path, cli.dict_format.as_ref().map(Into::into))?,
+        None => match cli.dict_type {
+            DictType::Pingshui => parse_pingshui(PINGSHUI_RHYME_DATA)?,
+            // 词林正韵上去不分，按平水韵的去声字检查去声
+            DictType::Cilin => parse_cilin(CILIN_RHYME_DATA)?
+                .with_qu_chars(parse_pingshui(PINGSHUI_RHYME_DATA)?.chars_by_sheng_diao(ShengDiao::Qu)),
+            DictType::Xinyun => parse_cilin(XINYUN_RHYME_DATA)?,
+        },
+    }.with_unknown_weight(cli.unknown_weight);
+    for path in &cli.yiti_file {
+        rhyme_dict = rhyme_dict.with_variants(load_yiti_file(path)?);
+    }
 
     match &cli.command {
         Commands::QueryCharRhyme { character, show_all} =>
             query_char_rhyme(&rhyme_dict, character, *show_all)?,
         Commands::QueryCiPai { ci_pai, variant } =>
-            query_cipai(ci_pai, variant.as_ref())?,
-        Commands::MatchCiPai {ci_pai, variant, text} =>
-            match_cipai(&rhyme_dict, ci_pai, variant, text)?,
-        Commands::SearchCiPai { top, text } =>
-            best_match_cipai(&rhyme_dict, *top, text)?,
+            query_cipai(&load_cipai_list(&cli.cipai_file, cli.strict)?, ci_pai, variant.as_ref())?,
+        Commands::MatchCiPai {ci_pai, variant, single_stanza, text} =>
+            match_cipai(&rhyme_dict, &load_cipai_list(&cli.cipai_file, cli.strict)?, ci_pai.as_deref(), variant.as_deref(),
+                        *single_stanza, text)?,
+        Commands::SearchCiPai { top, category, text } => {
+            let cipai_list = filter_category(load_cipai_list(&cli.cipai_file, cli.strict)?, category.as_ref());
+            best_match_cipai(&rhyme_dict, &cipai_list, *top, text)?
+        }
+        Commands::ValidateData => validate_data(&cli.cipai_file)?,
+        Commands::ListCiPai { category } =>
+            list_cipai(&filter_category(load_cipai_list(&cli.cipai_file, cli.strict)?, category.as_ref()))?,
     };
 
     Ok(())
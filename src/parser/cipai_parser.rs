@@ -1,174 +1,736 @@
-use crate::core::cipai::CiPai;
+use crate::core::cipai::{CiPai, CiPaiCategory, RhymeCounts};
+use crate::core::description::{CiPaiDescription, DescriptionSpan};
+use crate::core::meter::{Meter, Repeat};
+use crate::core::scheme::{PingZeLink, RhymeScheme};
 use anyhow::{bail, Context, Result};
-use crate::core::tone::{MeterTone, MeterToneType};
+use std::fs;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use crate::core::tone::{BasicTone, MeterTone, MeterToneKind, MeterToneType};
 
+/// 词牌解析中的问题，记录出错的词牌、变体、在文件中的行号和出错的字
+#[derive(Clone, Debug)]
+pub struct CiPaiDiagnostic {
+    pub name: String,
+    pub variant: Option<String>,
+    /// 在文件中的行号，从 1 开始
+    pub line: Option<usize>,
+    pub char: Option<char>,
+    pub message: String,
+}
+
+impl Display for CiPaiDiagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "（{}）", variant)?;
+        }
+        if let Some(line) = self.line {
+            write!(f, " 第{}行", line)?;
+        }
+        if let Some(c) = self.char {
+            write!(f, " “{}”", c)?;
+        }
+        write!(f, "：{}", self.message)
+    }
+}
+
+/// 词牌解析结果：解析成功的词牌，以及解析失败的格律的诊断信息
+#[derive(Default)]
+pub struct CiPaiParseResult {
+    pub cipai: Vec<CiPai>,
+    pub diagnostics: Vec<CiPaiDiagnostic>,
+    /// 格律已解析，但部分标记有误而忽略的诊断信息，如对偶句数为奇数时不检查对仗
+    pub warnings: Vec<CiPaiDiagnostic>,
+    /// 说明中所述、未能对应到任何格律的字数、韵数，及所在的词牌名
+    pub unattributed_counts: Vec<(String, StatedCounts)>,
+}
+
+impl CiPaiParseResult {
+    /// 严格模式：有任何格律解析失败即返回错误
+    pub fn into_strict(self) -> Result<Vec<CiPai>> {
+        if !self.diagnostics.is_empty() {
+            let messages: Vec<String> = self.diagnostics.iter().map(|d| d.to_string()).collect();
+            bail!("{}个词牌格律解析失败:\n{}", self.diagnostics.len(), messages.join("\n"));
+        }
+        Ok(self.cipai)
+    }
+}
+
+/// 解析词牌，忽略解析失败的格律。需要知道哪些格律解析失败时用 parse_cipai_with_diagnostics
 pub fn parse_cipai(content: &str) -> Result<Vec<CiPai>> {
-    let result = roxmltree::Document::parse(content)?
-        .descendants()
-        .filter(|n| n.has_tag_name("词牌"))
-        .flat_map(|n| {
-            let names : Vec<String> = n.descendants()
-                .filter(|c| c.has_tag_name("名称"))
-                .map(|c| c.text().unwrap_or("").into())
-                .collect();
-            let description = n.descendants()
-                .find(|c| c.has_tag_name("说明"))
-                .map(|c| get_combined_text(c));
-            let result: Result<Vec<CiPai>> = n.descendants()
-                .filter(|c| c.has_tag_name("格律"))
-                .map(|c| {
-                    let meter_str = c.text().context("xml文件中没有找到格律标签")?;
-                    let variant = c.attribute("说明").map(|t| t.into());
-                    let meter = parse_meter(meter_str)?;
-                    Ok(CiPai{
-                        names: names.clone(),
-                        variant,
-                        description: description.clone(),
-                        meter
-                    })
+    Ok(parse_cipai_with_diagnostics(content)?.cipai)
+}
+
+/// 解析词牌，返回解析成功的词牌及解析失败的格律的诊断信息。XML 本身有误时返回错误
+pub fn parse_cipai_with_diagnostics(content: &str) -> Result<CiPaiParseResult> {
+    let doc = roxmltree::Document::parse(content)?;
+    let mut result = CiPaiParseResult::default();
+    for n in doc.descendants().filter(|n| n.has_tag_name("词牌")) {
+        let names : Vec<String> = n.descendants()
+            .filter(|c| c.has_tag_name("名称"))
+            .map(|c| c.text().unwrap_or("").into())
+            .collect();
+        let category_name = n.ancestors()
+            .find(|a| a.has_tag_name("类别"))
+            .and_then(|a| a.children().find(|c| c.has_tag_name("名称")))
+            .and_then(|c| c.text());
+        let description = n.descendants()
+            .find(|c| c.has_tag_name("说明"))
+            .map(parse_description);
+        let stated_counts = description.as_ref()
+            .map(|d| parse_stated_counts(&d.text()))
+            .unwrap_or_default();
+        let related_links: Vec<(String, String)> = n.children()
+            .filter(|c| c.has_tag_name("相关链接"))
+            .flat_map(|c| parse_spans(c).into_iter())
+            .filter_map(|s| Some((s.text(), s.link_target()?)))
+            .collect();
+        let start = result.cipai.len();
+        // 第一个格律为定格，解析失败时为 false
+        let mut has_dingge = false;
+        for (i, c) in n.descendants().filter(|c| c.has_tag_name("格律")).enumerate() {
+            let variant: Option<String> = c.attribute("说明").map(|t| t.into());
+            let line = doc.text_pos_at(c.range().start).row as usize;
+            let mut warnings = vec![];
+            let cipai: Result<CiPai> = (|| {
+                let meter_str = c.text().context("xml文件中没有找到格律标签")?;
+                // 格律上的类别优先于所在类别
+                let category_name = c.attribute("类别").or(category_name);
+                let category = match category_name {
+                    Some(name) => Some(CiPaiCategory::from_name(name)
+                        .with_context(|| format!("未知的词牌类别: {}", name))?),
+                    None => None,
+                };
+                let mut meter = parse_meter(meter_str, &mut warnings)?;
+                // 附注与格律同在一个正文中
+                let notes = c.parent()
+                    .and_then(|p| p.children().find(|s| s.has_tag_name("附注")))
+                    .map(parse_description);
+                let texts: Vec<String> = description.iter().chain(notes.iter()).map(|d| d.text()).collect();
+                match category {
+                    Some(CiPaiCategory::TongYe) => meter.rhyme_scheme.link_ping_ze(PingZeLink::TongYe),
+                    Some(CiPaiCategory::CuoYe) if states_same_group(&texts) =>
+                        meter.rhyme_scheme.link_ping_ze(PingZeLink::CuoYe),
+                    _ => {}
+                }
+                meter.rhyme_scheme.change_group = category == Some(CiPaiCategory::ZhuanHuan);
+                let has_ze_rhyme = meter.rhyme_scheme.slots.iter().any(|s| s.tone == BasicTone::Ze);
+                meter.ru_rhyme = has_ze_rhyme && requires_ru_rhyme(variant.as_deref(), &texts);
+                Ok(CiPai{
+                    names: names.clone(),
+                    variant: variant.clone(),
+                    category,
+                    description: description.clone(),
+                    notes,
+                    related_links: related_links.clone(),
+                    char_count: None,
+                    rhyme_count: None,
+                    meter
                 })
-                .collect();
-            if result.is_err() {
-                eprintln!("Error to parse CiPai xml: {:?}", result.as_ref().err().unwrap())
+            })();
+            match cipai {
+                Ok(cipai) => {
+                    has_dingge |= i == 0;
+                    result.cipai.push(cipai);
+                    result.warnings.extend(warnings.into_iter().map(|w| CiPaiDiagnostic {
+                        name: names.first().cloned().unwrap_or_default(),
+                        variant: variant.clone(),
+                        line: Some(line + w.row.unwrap_or(0)),
+                        char: w.char,
+                        message: w.message,
+                    }));
+                }
+                Err(e) => {
+                    let (row, char, message) = match e.downcast::<MeterParseError>() {
+                        Ok(e) => (e.row, e.char, e.message),
+                        Err(e) => (None, None, e.to_string()),
+                    };
+                    result.diagnostics.push(CiPaiDiagnostic {
+                        name: names.first().cloned().unwrap_or_default(),
+                        variant,
+                        line: Some(line + row.unwrap_or(0)),
+                        char,
+                        message,
+                    });
+                }
             }
-            result.unwrap_or_default()
-        })
-        .collect();
+        }
+        let unattributed = attribute_stated_counts(stated_counts, &mut result.cipai[start..], has_dingge);
+        let name = names.first().cloned().unwrap_or_default();
+        result.unattributed_counts.extend(unattributed.into_iter().map(|s| (name.clone(), s)));
+    }
     Ok(result)
 }
 
-fn get_combined_text(node: roxmltree::Node) -> String {
-    let mut text = String::new();
-    // Recursively collect all text nodes, ignoring element tags
-    for child in node.descendants() {
+/// 将说明中所述的各种格式对应到所描述的格律，返回未能对应的。每种格式、每个格律至多对应一次。
+/// 先按顺序取字数相同、平仄韵相合的格律；第一种格式没有相合的格律时，所述为定格
+fn attribute_stated_counts(stated_counts: Vec<StatedCounts>, variants: &mut [CiPai], has_dingge: bool)
+    -> Vec<StatedCounts> {
+    let mut targets: Vec<Option<usize>> = vec![None; stated_counts.len()];
+    for (stated, target) in stated_counts.iter().zip(targets.iter_mut()) {
+        let fits = |c: &CiPai| c.char_count.is_none()
+            && c.meter.char_count() == stated.chars
+            && stated.rhymes.is_none_or(|r| r.fits(&c.meter));
+        *target = variants.iter().position(fits);
+        if let Some(i) = *target {
+            variants[i].char_count = Some(stated.chars);
+        }
+    }
+    if has_dingge && targets.first() == Some(&None) && variants[0].char_count.is_none() {
+        targets[0] = Some(0);
+    }
+    let mut unattributed = vec![];
+    for (stated, target) in stated_counts.into_iter().zip(targets) {
+        match target {
+            Some(i) => {
+                variants[i].char_count = Some(stated.chars);
+                variants[i].rhyme_count = stated.rhymes;
+            }
+            None => unattributed.push(stated),
+        }
+    }
+    unattributed
+}
+
+/// 从文件加载词牌，文件格式同内置的唐宋词格律 XML。忽略解析失败的格律
+pub fn load_cipai_file(path: impl AsRef<Path>) -> Result<Vec<CiPai>> {
+    Ok(load_cipai_file_with_diagnostics(path)?.cipai)
+}
+
+/// 从文件加载词牌，并返回解析失败的格律的诊断信息
+pub fn load_cipai_file_with_diagnostics(path: impl AsRef<Path>) -> Result<CiPaiParseResult> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("无法读取词牌文件: {}", path.display()))?;
+    parse_cipai_with_diagnostics(&content).with_context(|| format!("词牌文件解析失败: {}", path.display()))
+}
+
+/// 解析说明或附注：有段落时每个段落为一段，否则整体为一段
+fn parse_description(node: roxmltree::Node) -> CiPaiDescription {
+    let paragraphs: Vec<roxmltree::Node> = node.children().filter(|c| c.has_tag_name("段落")).collect();
+    let paragraphs = if paragraphs.is_empty() {
+        vec![parse_spans(node)]
+    } else {
+        paragraphs.into_iter().map(parse_spans).collect()
+    };
+    CiPaiDescription {
+        paragraphs: paragraphs.into_iter().filter(|p| !p.is_empty()).collect(),
+    }
+}
+
+fn parse_spans(node: roxmltree::Node) -> Vec<DescriptionSpan> {
+    let mut spans = vec![];
+    let children: Vec<roxmltree::Node> = node.children().collect();
+    for (i, child) in children.iter().enumerate() {
         if child.is_text() {
-            text.push_str(child.text().unwrap_or(""));
-        }
-    }
-    text.trim().into()
-}
-
-fn parse_meter(meter: &str) -> Result<Vec<Vec<MeterTone>>> {
-    let delimiters = vec!['。',  '，',  '、', '\n'];
-    let mut meter_str = meter
-        // 忽略对偶句
-        .replace("{", "")
-        .replace("｛", "")
-        .replace("}", "")
-        .replace("｝", "")
-        // 忽略衬字
-        .replace("ˇ", "")
-        // 忽略领格字
-        .replace("～", "")
-        .replace("！", "")
-        // 忽略领叠韵句
-        .replace("[", "")
-        .replace("［", "")
-        .replace("]", "")
-        .replace("］", "")
-        // 忽略可选可省略
-        .replace("（", "")
-        .replace("）", "")
-        // 忽略可选增韵
-        .replace("＃", "")
-        // remove spaces
-        .replace(" ", "");
-    for d in &delimiters {
-        let pattern = format!("{}\n", d);
-        meter_str = meter_str.replace(&pattern, &d.to_string());
-    }
-    let mut result = meter_str.split(|c| delimiters.contains(&c))
-        .map(parse_meter_line)
-        .collect::<Result<Vec<Vec<MeterTone>>>>()?;
-    // remove empty lines at the end
-    while result.last().is_some() && result.last().unwrap().is_empty() {
-        result.pop();
+            let mut text = child.text().unwrap_or("");
+            // 去掉首尾的排版空白
+            if i == 0 {
+                text = text.trim_start();
+            }
+            if i == children.len() - 1 {
+                text = text.trim_end();
+            }
+            if !text.is_empty() {
+                spans.push(DescriptionSpan::Text(text.into()));
+            }
+            continue;
+        }
+        let content = parse_spans(*child);
+        let span = match child.tag_name().name() {
+            "专名" => DescriptionSpan::Name(content.iter().map(|s| s.text()).collect()),
+            "引用" => DescriptionSpan::Quote(content),
+            "着重" => DescriptionSpan::Emphasis(content),
+            "注释" => DescriptionSpan::Note(content),
+            "链接" => DescriptionSpan::Link {
+                target: child.attribute("xhref").unwrap_or("").into(),
+                content,
+            },
+            // 其他标签（如注释中嵌套的段落）只保留内容
+            _ => {
+                spans.extend(content);
+                continue;
+            }
+        };
+        spans.push(span);
     }
-    Ok(result)
+    spans
 }
 
-fn parse_meter_line(line: &str) -> Result<Vec<MeterTone>> {
-    let chars: Vec<char> = line.chars().collect();
-    let mut result = vec![];
-    let mut i = 0;
-    while i < chars.len() {
-        let tone = if chars[i] == '－' {
-            if i+1 < chars.len() {
-                if chars[i+1] == '％' {
-                    i += 1;
-                    MeterTone {
-                        tone: MeterToneType::Ping,
-                        rhyme_num: Some(0),
-                    }
-                } else if chars[i+1] == '＆' {
+/// 是否要求仄韵用入声韵：变体名写明入声韵，或说明、附注中写明“例用入声部韵”、“以入声韵为宜”等。
+/// 变体名写明上、去声韵的除外；“改用入声韵”、“原用入声韵”、“忌用入声部韵”说的不是本调
+fn requires_ru_rhyme(variant: Option<&str>, texts: &[String]) -> bool {
+    if let Some(variant) = variant {
+        if variant.contains("入声") {
+            return true;
+        }
+        if variant.contains("上、去声") || variant.contains("上去声") {
+            return false;
+        }
+    }
+    texts.iter()
+        .flat_map(|t| t.split(['。', '，', '；']))
+        .any(|clause| clause.contains("入声")
+            && (clause.contains('韵') || clause.contains('部'))
+            && !["改用", "改作", "原用", "忌用"].iter().any(|w| clause.contains(w)))
+}
+
+/// 说明、附注中是否写明平仄韵同部，如“同部参差错叶”、“同部互协”。
+/// “不同部换叶”及“或同部平仄互叶”这类两可之说不算
+fn states_same_group(texts: &[String]) -> bool {
+    texts.iter()
+        .flat_map(|t| t.split(['。', '，', '；']))
+        .any(|clause| clause.contains("同部") && !clause.contains("不同部") && !clause.starts_with('或')
+            && (clause.contains('叶') || clause.contains('协')))
+}
+
+const CHINESE_DIGITS: [char; 10] = ['零', '一', '二', '三', '四', '五', '六', '七', '八', '九'];
+
+fn is_chinese_numeral(c: char) -> bool {
+    CHINESE_DIGITS.contains(&c) || "〇两十廿卅卌百".contains(c)
+}
+
+/// 解析中文数字，如“廿六”、“一百三十四”、“一百一”（即一百零一）
+pub fn parse_chinese_number(s: &str) -> Option<usize> {
+    if s.is_empty() {
+        return None;
+    }
+    let mut total = 0;
+    let mut digit = None;
+    for c in s.chars() {
+        match c {
+            '百' => {
+                total += digit.unwrap_or(1) * 100;
+                digit = None;
+            }
+            '十' => {
+                total += digit.unwrap_or(1) * 10;
+                digit = None;
+            }
+            '廿' => total += 20,
+            '卅' => total += 30,
+            '卌' => total += 40,
+            '两' => digit = Some(2),
+            '〇' => digit = Some(0),
+            _ => digit = Some(CHINESE_DIGITS.iter().position(|&d| d == c)?),
+        }
+    }
+    Some(total + digit.unwrap_or(0))
+}
+
+/// 说明中所述的一种格式的字数及韵数
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatedCounts {
+    pub chars: usize,
+    /// 没有写韵数时为 None
+    pub rhymes: Option<RhymeCounts>,
+    /// 写明字数的句子
+    pub text: String,
+}
+
+impl Display for StatedCounts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}字（“{}”）", self.chars, self.text)
+    }
+}
+
+/// 从说明中解析所述的字数及其后的韵数，如“五十四字，前后片各四平韵”解析为 54 字、8 平韵。
+/// 说明可能先后写了几种格式，每种一项。韵数只取字数之后第一个写了韵数的句子
+pub fn parse_stated_counts(text: &str) -> Vec<StatedCounts> {
+    let mut result: Vec<StatedCounts> = vec![];
+    // 当前格式的韵数已写完
+    let mut closed = false;
+    for sentence in text.split('。') {
+        let mut multiplier = 1;
+        for clause in sentence.split(['，', '；', '：']) {
+            // “亦有……者”说的是别的格式
+            if clause.starts_with("亦有") || clause.starts_with("或") {
+                continue;
+            }
+            // 指明某一片的分句之后，不再沿用前面的倍数，如“前后片各四平韵，后片第四句……，必须押一同部仄韵”
+            if clause.contains('片') || clause.contains('段') {
+                multiplier = 1;
+            }
+            let chars: Vec<char> = clause.chars().collect();
+            let mut i = 0;
+            while i < chars.len() {
+                if !is_chinese_numeral(chars[i]) {
                     i += 1;
-                    MeterTone {
-                        tone: MeterToneType::Ping,
-                        rhyme_num: Some(1),
-                    }
-                } else if chars[i+1] >= 'a' && chars[i+1] <= 'z' {
+                    continue;
+                }
+                let start = i;
+                while i < chars.len() && is_chinese_numeral(chars[i]) {
                     i += 1;
-                    MeterTone {
-                        tone: MeterToneType::Ping,
-                        rhyme_num: Some((chars[i] as i32)-('a' as i32)+1),
-                    }
-                } else {
-                    MeterTone {
-                        tone: MeterToneType::Ping,
-                        rhyme_num: None,
-                    }
                 }
-            } else {
-                MeterTone {
-                    tone: MeterToneType::Ping,
-                    rhyme_num: None,
+                let Some(number) = parse_chinese_number(&chars[start..i].iter().collect::<String>()) else {
+                    continue;
+                };
+                let prev = if start > 0 { Some(chars[start - 1]) } else { None };
+                // “第三字”、“各添一字”等不是字数
+                if prev.is_some_and(|p| "第添增减少多".contains(p)) {
+                    continue;
+                }
+                let mut next = chars.get(i).copied();
+                if next == Some('字') && number >= 14 {
+                    result.push(StatedCounts { chars: number, rhymes: None, text: sentence.trim().to_string() });
+                    closed = false;
+                    continue;
+                }
+                // “押一同部仄韵”
+                if chars[i..].starts_with(&['同', '部']) {
+                    i += 2;
+                    next = chars.get(i).copied();
+                }
+                let is_rhyme = match next {
+                    Some('韵') => true,
+                    Some('平' | '仄' | '叠') => chars.get(i + 1) == Some(&'韵'),
+                    _ => false,
+                };
+                if !is_rhyme || number > 40 || closed {
+                    continue;
                 }
+                let Some(last) = result.last_mut() else {
+                    continue;
+                };
+                let qualifier: String = chars[..start].iter().collect();
+                multiplier = get_rhyme_multiplier(text, &qualifier).unwrap_or(multiplier);
+                let rhymes = last.rhymes.get_or_insert_default();
+                let count = match next {
+                    Some('平') => &mut rhymes.ping,
+                    Some('仄') => &mut rhymes.ze,
+                    _ => &mut rhymes.other,
+                };
+                *count += number * multiplier;
             }
-        } else if chars[i] == '│' || chars[i] ==  '去' {
-            if i + 1 < chars.len() {
-                if chars[i+1] == '＊' {
-                    i += 1;
-                    MeterTone {
-                        tone: MeterToneType::Ze,
-                        rhyme_num: Some(0),
-                    }
-                } else if chars[i+1] ==  '☆' {
-                    i += 1;
-                    MeterTone {
-                        tone: MeterToneType::Ze,
-                        rhyme_num: Some(1),
-                    }
-                } else if chars[i+1] ==  '★' {
-                    i += 1;
-                    MeterTone {
-                        tone: MeterToneType::Ze,
-                        rhyme_num: Some(2),
-                    }
-                } else if chars[i+1] >= 'a' && chars[i+1] <= 'z' {
-                    i += 1;
-                    MeterTone {
-                        tone: MeterToneType::Ze,
-                        rhyme_num: Some((chars[i] as i32)-('a' as i32)+1),
-                    }
+        }
+        if result.last().is_some_and(|s| s.rhymes.is_some()) {
+            closed = true;
+        }
+    }
+    result
+}
+
+/// “前后片各四平韵”、“第一、二段各四仄韵”、“前段、中段四仄韵”等，韵数要乘以片数。没有指明哪一片时返回 None，
+/// 沿用同一句中前面的倍数，如“前后片各两仄韵，两平韵”
+fn get_rhyme_multiplier(text: &str, qualifier: &str) -> Option<usize> {
+    if qualifier.contains('每') {
+        // “分四段，每段各四仄韵”
+        let count = text.match_indices('分')
+            .map(|(i, _)| text[i + '分'.len_utf8()..].chars())
+            .find_map(|rest| {
+                let n: String = rest.clone().take_while(|c| is_chinese_numeral(*c)).collect();
+                (rest.clone().nth(n.chars().count()) == Some('段')).then(|| parse_chinese_number(&n))?
+            });
+        return Some(count.unwrap_or(2));
+    }
+    if qualifier.contains('各') || qualifier.contains('皆') {
+        return Some(if qualifier.contains('第') { qualifier.matches('、').count() + 1 } else { 2 });
+    }
+    if qualifier.contains('片') || qualifier.contains('段') {
+        return Some(qualifier.matches("片、").count() + qualifier.matches("段、").count() + 1);
+    }
+    None
+}
+
+/// 格律解析错误，记录出错处在格律中的行（从 0 开始）和出错的字
+#[derive(Debug)]
+pub struct MeterParseError {
+    pub message: String,
+    pub row: Option<usize>,
+    pub char: Option<char>,
+}
+
+impl MeterParseError {
+    fn new(message: impl Into<String>) -> Self {
+        MeterParseError { message: message.into(), row: None, char: None }
+    }
+
+    fn with_char(mut self, c: char) -> Self {
+        self.char = Some(c);
+        self
+    }
+
+    /// 记录出错的行，已有行号时不覆盖
+    fn at(mut self, row: usize) -> Self {
+        self.row = self.row.or(Some(row));
+        self
+    }
+
+    /// 给任意错误加上行号
+    fn locate(err: anyhow::Error, row: usize) -> anyhow::Error {
+        match err.downcast::<MeterParseError>() {
+            Ok(e) => e.at(row).into(),
+            Err(e) => MeterParseError::new(e.to_string()).at(row).into(),
+        }
+    }
+}
+
+impl Display for MeterParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for MeterParseError {}
+
+/// 去掉紧跟在 d 后的换行，同 str::replace("d\n", "d")
+fn remove_newline_after(chars: Vec<(char, usize)>, d: char) -> Vec<(char, usize)> {
+    let mut result = Vec::with_capacity(chars.len());
+    let mut i = 0;
+    while i < chars.len() {
+        result.push(chars[i]);
+        if chars[i].0 == d && chars.get(i + 1).is_some_and(|(c, _)| *c == '\n') {
+            i += 1;
+        }
+        i += 1;
+    }
+    result
+}
+
+/// 解析格律。有误而可以忽略的标记记入 warnings，不影响其余部分
+fn parse_meter(meter: &str, warnings: &mut Vec<MeterParseError>) -> Result<Meter> {
+    let delimiters = ['。',  '，', '\n'];
+    // 每个字及其所在的行
+    let mut meter_chars = vec![];
+    let mut row = 0;
+    for c in meter.chars() {
+        // 忽略可选增韵和空格
+        if c != '＃' && c != ' ' {
+            meter_chars.push((c, row));
+        }
+        if c == '\n' {
+            row += 1;
+        }
+    }
+    for d in delimiters.iter().chain([DOU].iter()) {
+        meter_chars = remove_newline_after(meter_chars, *d);
+    }
+    let mut sentences = vec![(String::new(), None)];
+    for (c, row) in meter_chars {
+        let (sentence, sentence_row) = sentences.last_mut().unwrap();
+        if delimiters.contains(&c) {
+            sentence_row.get_or_insert(row);
+            sentences.push((String::new(), None));
+        } else {
+            sentence.push(c);
+            sentence_row.get_or_insert(row);
+        }
+    }
+    let mut lines = vec![];
+    // 每句所在的行
+    let mut line_rows = vec![];
+    let mut duiou = vec![];
+    let mut duiou_start = None;
+    let mut repeat_spans = vec![];
+    let mut repeat_start = None;
+    // 当前所在的可省略段的编号，及已有的可省略段数
+    let mut group: Option<usize> = None;
+    let mut group_count = 0;
+    let mut optional_row = 0;
+    let mut stanza_starts = vec![0];
+    let mut rhyme_scheme = RhymeScheme::default();
+    for (line, row) in &sentences {
+        let row = row.unwrap_or(0);
+        let line_idx = lines.len();
+        // 空行分片
+        if line.is_empty() {
+            if line_idx > 0 && stanza_starts.last() != Some(&line_idx) {
+                stanza_starts.push(line_idx);
+            }
+            continue;
+        }
+        let fail = |message: String, c: char| MeterParseError::new(message).with_char(c).at(row);
+        let locate = |err| MeterParseError::locate(err, row);
+        if let Some(c) = line.chars().find(|c| DUIOU_START.contains(c)) {
+            if duiou_start.is_some() {
+                return Err(fail(format!("对偶句标记嵌套: {}", line), c).into());
+            }
+            duiou_start = Some(line_idx);
+        }
+        let duiou_end = line.contains(DUIOU_END);
+        let line = line.replace(DUIOU_START, "").replace(DUIOU_END, "");
+        // 叠韵句、可省略标记和豆可以出现在句中，按标记分段解析以记录标记位置
+        let mut tones = vec![];
+        let mut segment = String::new();
+        for c in line.chars() {
+            if c == DOU {
+                tones.extend(parse_optional_segment(&segment, group, &mut rhyme_scheme).map_err(locate)?);
+                segment.clear();
+                let tone: &mut MeterTone = tones.last_mut()
+                    .ok_or_else(|| fail(format!("豆前没有字: {}", line), c))?;
+                tone.dou = true;
+            } else if OPTIONAL_START.contains(&c) || OPTIONAL_END.contains(&c) {
+                tones.extend(parse_optional_segment(&segment, group, &mut rhyme_scheme).map_err(locate)?);
+                segment.clear();
+                let start = OPTIONAL_START.contains(&c);
+                if start == group.is_some() {
+                    return Err(fail(format!("可省略标记不匹配: {}", line), c).into());
+                }
+                group = if start {
+                    group_count += 1;
+                    Some(group_count - 1)
                 } else {
-                    MeterTone {
-                        tone: MeterToneType::Ze,
-                        rhyme_num: None,
+                    None
+                };
+                optional_row = row;
+            } else if REPEAT_START.contains(&c) || REPEAT_END.contains(&c) {
+                tones.extend(parse_optional_segment(&segment, group, &mut rhyme_scheme).map_err(locate)?);
+                segment.clear();
+                if REPEAT_START.contains(&c) {
+                    if repeat_start.is_some() {
+                        return Err(fail(format!("叠韵句标记嵌套: {}", line), c).into());
                     }
+                    repeat_start = Some((line_idx, tones.len()));
+                } else {
+                    let start = repeat_start.take()
+                        .ok_or_else(|| fail(format!("叠韵句缺少起始标记: {}", line), c))?;
+                    repeat_spans.push((start, (line_idx, tones.len())));
                 }
             } else {
-                MeterTone {
-                    tone: MeterToneType::Ze,
-                    rhyme_num: None,
-                }
+                segment.push(c);
             }
-        } else if chars[i] == '＋' {
-            MeterTone {
-                tone: MeterToneType::Zhong,
-                rhyme_num: None,
+        }
+        tones.extend(parse_optional_segment(&segment, group, &mut rhyme_scheme).map_err(locate)?);
+        lines.push(tones);
+        line_rows.push(row);
+        if duiou_end {
+            let start = duiou_start.take()
+                .ok_or_else(|| fail(format!("对偶句缺少起始标记: {}", line), DUIOU_END[1]))?;
+            // 对偶句数有误时只忽略这一组对偶
+            match get_duiou_pairs(start, line_idx) {
+                Ok(pairs) => duiou.extend(pairs),
+                Err(e) => warnings.push(MeterParseError::new(e.to_string()).with_char(DUIOU_START[1])
+                    .at(line_rows[start])),
             }
+        }
+    }
+    if let Some(start) = duiou_start {
+        return Err(MeterParseError::new("对偶句缺少结束标记").with_char(DUIOU_START[1]).at(line_rows[start]).into());
+    }
+    if let Some((start, _)) = repeat_start {
+        return Err(MeterParseError::new("叠韵句缺少结束标记").with_char(REPEAT_START[1]).at(line_rows[start]).into());
+    }
+    if group.is_some() {
+        return Err(MeterParseError::new("可省略标记缺少结束标记").with_char(OPTIONAL_START[1]).at(optional_row).into());
+    }
+    // remove the stanza started by empty lines at the end
+    stanza_starts.retain(|&s| s < lines.len());
+    let repeats = repeat_spans.into_iter()
+        .map(|(start, end)| get_repeat(&lines, &stanza_starts, start, end)
+            .map_err(|e| MeterParseError::locate(e, line_rows[start.0])))
+        .collect::<Result<Vec<Repeat>>>()?;
+    Ok(Meter { lines, duiou, repeats, stanza_starts, rhyme_scheme, ru_rhyme: false })
+}
+
+const DUIOU_START: [char; 2] = ['{', '｛'];
+const DUIOU_END: [char; 2] = ['}', '｝'];
+
+/// 豆，句中停顿，不分句
+const DOU: char = '、';
+
+const OPTIONAL_START: [char; 2] = ['(', '（'];
+const OPTIONAL_END: [char; 2] = [')', '）'];
+
+/// 解析一段格律，group 为所在可省略段的编号，不在可省略段中时为 None
+fn parse_optional_segment(segment: &str, group: Option<usize>, scheme: &mut RhymeScheme) -> Result<Vec<MeterTone>> {
+    let mut tones = parse_meter_line(segment, scheme)?;
+    if group.is_some() {
+        for tone in tones.iter_mut() {
+            tone.optional = true;
+            tone.group = group;
+        }
+    }
+    Ok(tones)
+}
+
+const REPEAT_START: [char; 2] = ['[', '［'];
+const REPEAT_END: [char; 2] = [']', '］'];
+
+/// 叠韵句标记内的字重复前一句末尾相同数量的字（如跨句则为前面各句），不跨越分片。
+/// 如醉花间“休相问，怕相问”，第二句末字叠第一句末字。
+/// 如标记前没有足够的字，如潇湘神开头两句，则标记内后一半重复前一半。
+fn get_repeat(lines: &[Vec<MeterTone>], stanza_starts: &[usize], start: (usize, usize), end: (usize, usize))
+        -> Result<Repeat> {
+    let mut target = vec![];
+    for (line_idx, line) in lines.iter().enumerate().take(end.0 + 1).skip(start.0) {
+        let from = if line_idx == start.0 { start.1 } else { 0 };
+        let to = if line_idx == end.0 { end.1 } else { line.len() };
+        target.extend((from..to).map(|i| (line_idx, i)));
+    }
+    if target.is_empty() {
+        bail!("叠韵句为空: 第{}句", start.0 + 1);
+    }
+
+    let mut source = vec![];
+    let (mut line_idx, mut i) = (start.0, 0);
+    while source.len() < target.len() {
+        if i > 0 {
+            i -= 1;
+            source.push((line_idx, i));
+        } else if line_idx > 0 && !stanza_starts.contains(&line_idx) {
+            line_idx -= 1;
+            i = lines[line_idx].len();
+        } else {
+            break;
+        }
+    }
+    source.reverse();
+
+    if source.len() < target.len() {
+        if !target.len().is_multiple_of(2) {
+            bail!("叠韵句前没有足够的字: 第{}句", start.0 + 1);
+        }
+        let source = target[..target.len() / 2].to_vec();
+        let target = target[target.len() / 2..].to_vec();
+        return Ok(Repeat { source, target });
+    }
+    Ok(Repeat { source, target })
+}
+
+/// 对偶标记内的句子两两相对。多于两句时为隔句对，如四句时第一句对第三句，第二句对第四句
+fn get_duiou_pairs(start: usize, end: usize) -> Result<Vec<(usize, usize)>> {
+    let count = end - start + 1;
+    if !count.is_multiple_of(2) {
+        bail!("对偶句数量不是偶数: 第{}句至第{}句", start + 1, end + 1);
+    }
+    let half = count / 2;
+    Ok((start..start + half).map(|i| (i, i + half)).collect())
+}
+
+/// 平韵、仄韵的韵脚符号，小写字母为换韵时各韵段的韵脚
+const PING_RHYME_MARKERS: [char; 2] = ['％', '＆'];
+const ZE_RHYME_MARKERS: [char; 3] = ['＊', '☆', '★'];
+
+fn parse_meter_line(line: &str, scheme: &mut RhymeScheme) -> Result<Vec<MeterTone>> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut result = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let tone = if chars[i] == '－' || chars[i] == '│' || chars[i] == '去' {
+            let (tone, basic, markers) = match chars[i] {
+                '－' => (MeterToneType::Ping, BasicTone::Ping, &PING_RHYME_MARKERS[..]),
+                '│' => (MeterToneType::Ze, BasicTone::Ze, &ZE_RHYME_MARKERS[..]),
+                _ => (MeterToneType::Qu, BasicTone::Ze, &ZE_RHYME_MARKERS[..]),
+            };
+            let rhyme = match chars.get(i + 1) {
+                Some(&m) if markers.contains(&m) || m.is_ascii_lowercase() => {
+                    i += 1;
+                    Some(scheme.slot(m, basic))
+                }
+                _ => None,
+            };
+            MeterTone { rhyme, ..MeterTone::new(tone) }
+        } else if chars[i] == '！' || chars[i] == '～' {
+            let tone = if chars[i] == '！' { MeterToneType::Ze } else { MeterToneType::Ping };
+            MeterTone { kind: MeterToneKind::Lingzi, ..MeterTone::new(tone) }
+        } else if chars[i] == 'ˇ' {
+            MeterTone { optional: true, kind: MeterToneKind::Chenzi, ..MeterTone::new(MeterToneType::Zhong) }
+        } else if chars[i] == '＋' {
+            MeterTone::new(MeterToneType::Zhong)
         } else {
-            bail!("unexpected char in meter: \"{}\", whole sentence: {}", chars[i], line);
+            return Err(MeterParseError::new(format!("格律中有无法识别的字: {}", line))
+                .with_char(chars[i]).into());
         };
         result.push(tone);
         i += 1;
@@ -0,0 +1,530 @@
+use crate::core::cipai::{merge_cipai, CiPai, CiPaiCategory, RhymeCounts};
+use crate::core::meter::Repeat;
+use crate::core::scheme::{PingZeLink, RhymeSlot};
+use crate::core::tone::{BasicTone, MeterToneKind, MeterToneType};
+use crate::parser::cipai_parser::{load_cipai_file, parse_chinese_number, parse_cipai, parse_cipai_with_diagnostics,
+                                  parse_stated_counts};
+
+const CUSTOM_CIPAI: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<唐宋词格律>
+	<类别>
+		<名称>平韵格</名称>
+		<词牌>
+			<名称>十六字令</名称>
+			<说明><段落>自定义定格。</段落></说明>
+			<正文>
+				<格律 说明="定格"><![CDATA[－％。
+－－│，
+＋││－－％。]]></格律>
+				<格律 说明="自度"><![CDATA[－％。
+＋│－－％。]]></格律>
+			</正文>
+		</词牌>
+		<词牌>
+			<名称>自度曲</名称>
+			<正文>
+				<格律 说明="定格"><![CDATA[＋│－－％。]]></格律>
+			</正文>
+		</词牌>
+	</类别>
+</唐宋词格律>
+"#;
+
+fn builtin_cipai() -> String {
+    std::fs::read_to_string("data/cipai/cipai.xml").expect("Should read data file")
+}
+
+#[test]
+fn test_parse_builtin_cipai() {
+    let cipai_list = parse_cipai(&builtin_cipai()).expect("Should parse successfully");
+    let cipai = cipai_list.iter().find(|c| c.names[0] == "十六字令").unwrap();
+    assert_eq!(cipai.variant, Some("定格".to_string()));
+    assert_eq!(cipai.names, vec!["十六字令", "苍梧谣", "归字谣"]);
+}
+
+#[test]
+fn test_merge_custom_cipai() {
+    let base = parse_cipai(&builtin_cipai()).unwrap();
+    let base_len = base.len();
+    let custom = parse_cipai(CUSTOM_CIPAI).unwrap();
+    let merged = merge_cipai(base, custom);
+
+    // 定格 is replaced, 自度 and 自度曲 are added
+    assert_eq!(merged.len(), base_len + 2);
+    let variants: Vec<_> = merged.iter()
+        .filter(|c| c.names[0] == "十六字令")
+        .map(|c| c.variant.clone().unwrap())
+        .collect();
+    assert_eq!(variants, vec!["定格", "自度"]);
+    let dingge = merged.iter()
+        .find(|c| c.names[0] == "十六字令" && c.variant.as_deref() == Some("定格"))
+        .unwrap();
+    assert_eq!(dingge.description.as_ref().map(|d| d.text()), Some("自定义定格。".to_string()));
+    assert_eq!(dingge.meter.lines.len(), 3);
+    assert_eq!(merged.last().unwrap().names[0], "自度曲");
+    // 自定义词牌只写了正名，保留内置的别名
+    assert_eq!(dingge.names, vec!["十六字令", "苍梧谣", "归字谣"]);
+}
+
+#[test]
+fn test_merge_custom_cipai_by_main_name() {
+    let base = parse_cipai(&builtin_cipai()).unwrap();
+    let builtin_names = base.iter().find(|c| c.names[0] == "忆江南").unwrap().names.clone();
+    assert!(builtin_names.contains(&"望江南".to_string()));
+    let custom = parse_cipai(r#"<?xml version="1.0" encoding="UTF-8"?>
+<唐宋词格律>
+	<类别>
+		<名称>平韵格</名称>
+		<词牌>
+			<名称>忆江南</名称>
+			<正文>
+				<格律 说明="定格"><![CDATA[－％。
+＋│－－％。]]></格律>
+			</正文>
+		</词牌>
+		<词牌>
+			<名称>江南好</名称>
+			<名称>新名</名称>
+			<正文>
+				<格律 说明="自度"><![CDATA[＋│－－％。]]></格律>
+			</正文>
+		</词牌>
+	</类别>
+</唐宋词格律>
+"#).unwrap();
+    let merged = merge_cipai(base, custom);
+
+    // 覆盖的定格和按别名新增的变体都保留内置的正名和别名，仍为同一词牌
+    let first = merged.iter().position(|c| c.names[0] == "忆江南").unwrap();
+    let variants: Vec<&CiPai> = merged[first..].iter().take_while(|c| merged[first].is_same_cipai(c)).collect();
+    let names: Vec<_> = variants.iter().map(|c| c.variant.clone().unwrap()).collect();
+    assert_eq!(names, vec!["定格", "双调", "自度"]);
+    assert!(variants.iter().all(|c| c.names.starts_with(&builtin_names)));
+    assert_eq!(variants[0].meter.lines.len(), 2);
+    assert!(variants[0].has_name("望江南"));
+    assert_eq!(variants[2].names.last().map(|n| n.as_str()), Some("新名"));
+}
+
+#[test]
+fn test_load_cipai_file_not_found() {
+    let result = load_cipai_file("non_existent_file.xml");
+    assert!(result.is_err(), "Should fail for non-existent file");
+}
+
+#[test]
+fn test_parse_duiou() {
+    let cipai_list = parse_cipai(&builtin_cipai()).unwrap();
+    let yugezi = cipai_list.iter().find(|c| c.names[0] == "渔歌子").unwrap();
+    assert_eq!(yugezi.meter.duiou, vec![(2, 3)]);
+
+    // 隔句对
+    let qinyuanchun = cipai_list.iter().find(|c| c.names[0] == "沁园春").unwrap();
+    assert_eq!(qinyuanchun.meter.duiou[..2], [(3, 5), (4, 6)]);
+}
+
+#[test]
+fn test_parse_odd_duiou() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<唐宋词格律>
+	<类别>
+		<名称>平韵格</名称>
+		<词牌>
+			<名称>自度曲</名称>
+			<正文>
+				<格律 说明="定格"><![CDATA[｛＋│－－，
+＋－－│，
+＋││－％｝。
+＋│－－％。]]></格律>
+			</正文>
+		</词牌>
+	</类别>
+</唐宋词格律>
+"#;
+    // 对偶句数为奇数时只忽略对偶标记，格律照常解析，并报告标记有误
+    let result = parse_cipai_with_diagnostics(xml).unwrap();
+    assert!(result.diagnostics.is_empty());
+    assert_eq!(result.cipai.len(), 1);
+    assert_eq!(result.cipai[0].meter.lines.len(), 4);
+    assert!(result.cipai[0].meter.duiou.is_empty());
+    assert_eq!(result.warnings.len(), 1);
+    let warning = &result.warnings[0];
+    assert_eq!(warning.variant.as_deref(), Some("定格"));
+    assert_eq!(warning.line, Some(8));
+    assert_eq!(warning.char, Some('｛'));
+    assert!(warning.message.contains("不是偶数"));
+}
+
+#[test]
+fn test_parse_repeats() {
+    let cipai_list = parse_cipai(&builtin_cipai()).unwrap();
+    let rumengling = cipai_list.iter().find(|c| c.names[0] == "如梦令").unwrap();
+    assert_eq!(rumengling.meter.repeats, vec![Repeat {
+        source: vec![(4, 0), (4, 1)],
+        target: vec![(5, 0), (5, 1)],
+    }]);
+
+    // 叠韵只重复前一句的末字
+    let zuihuajian = cipai_list.iter().find(|c| c.names[0] == "醉花间").unwrap();
+    assert_eq!(zuihuajian.meter.repeats[0], Repeat { source: vec![(0, 2)], target: vec![(1, 2)] });
+
+    // 开头两句相叠
+    let xiaoxiangshen = cipai_list.iter().find(|c| c.names[0] == "潇湘神").unwrap();
+    assert_eq!(xiaoxiangshen.meter.repeats[0].source[0], (0, 0));
+    assert_eq!(xiaoxiangshen.meter.repeats[0].target[0], (1, 0));
+}
+
+#[test]
+fn test_parse_optional() {
+    let cipai_list = parse_cipai(&builtin_cipai()).unwrap();
+    // 可省略的豆不影响字
+    let xingxiangzi = cipai_list.iter().find(|c| c.names[0] == "行香子").unwrap();
+    assert!(xingxiangzi.meter.lines.iter().flatten()
+        .all(|t| !t.optional || t.kind == MeterToneKind::Chenzi));
+
+    let custom = parse_cipai(&CUSTOM_CIPAI.replace("－－│，", "（－－│），")).unwrap();
+    let optional: Vec<bool> = custom[0].meter.lines[1].iter().map(|t| t.optional).collect();
+    assert_eq!(optional, vec![true, true, true]);
+    assert!(custom[0].meter.lines[2].iter().all(|t| !t.optional));
+
+    // 每个可省略段有各自的编号
+    let custom = parse_cipai(&CUSTOM_CIPAI.replace("－－│，", "（－）－（ˇ│），")).unwrap();
+    let groups: Vec<Option<usize>> = custom[0].meter.lines[1].iter().map(|t| t.group).collect();
+    assert_eq!(groups, vec![Some(0), None, Some(1), Some(1)]);
+    assert!(custom[0].meter.lines.iter().flatten().filter(|t| t.group.is_none()).all(|t| !t.optional));
+}
+
+#[test]
+fn test_parse_lingzi_and_chenzi() {
+    let cipai_list = parse_cipai(&builtin_cipai()).unwrap();
+    let qinyuanchun = cipai_list.iter().find(|c| c.names[0] == "沁园春").unwrap();
+    let lingzi = &qinyuanchun.meter.lines[3][0];
+    assert_eq!(lingzi.kind, MeterToneKind::Lingzi);
+    assert_eq!(lingzi.tone, MeterToneType::Ze);
+    assert_eq!(qinyuanchun.meter.lines[3].len(), 5);
+
+    let yijiangnan = cipai_list.iter().find(|c| c.names[0] == "忆江南").unwrap();
+    let chenzi = &yijiangnan.meter.lines[1][2];
+    assert_eq!(chenzi.kind, MeterToneKind::Chenzi);
+    assert_eq!(chenzi.tone, MeterToneType::Zhong);
+    assert!(chenzi.optional);
+    assert_eq!(chenzi.group, None);
+}
+
+#[test]
+fn test_parse_dou() {
+    let cipai_list = parse_cipai(&builtin_cipai()).unwrap();
+    let xingxiangzi = cipai_list.iter().find(|c| c.names[0] == "行香子").unwrap();
+    // 第三句“＋－＋（、）＋│－－％”为一句
+    let line = &xingxiangzi.meter.lines[2];
+    assert_eq!(line.len(), 7);
+    let dou: Vec<bool> = line.iter().map(|t| t.dou).collect();
+    assert_eq!(dou, vec![false, false, true, false, false, false, false]);
+}
+
+#[test]
+fn test_parse_stanzas() {
+    let cipai_list = parse_cipai(&builtin_cipai()).unwrap();
+    let nangezi = cipai_list.iter()
+        .find(|c| c.names[0] == "南歌子" && c.variant.as_deref() == Some("双调"))
+        .unwrap();
+    assert_eq!(nangezi.meter.stanza_starts, vec![0, 4]);
+    assert!(nangezi.meter.lines.iter().all(|l| !l.is_empty()));
+    assert_eq!(nangezi.meter.duiou, vec![(0, 1), (4, 5)]);
+
+    let yugezi = cipai_list.iter().find(|c| c.names[0] == "渔歌子").unwrap();
+    assert_eq!(yugezi.meter.stanza_starts, vec![0]);
+}
+
+#[test]
+fn test_parse_description() {
+    let cipai_list = parse_cipai(&builtin_cipai()).unwrap();
+    let anxiang = cipai_list.iter().find(|c| c.names[0] == "暗香").unwrap();
+    let description = anxiang.description.as_ref().unwrap();
+    assert_eq!(description.paragraphs.len(), 1);
+    assert!(description.names().contains(&"石湖".to_string()));
+    assert_eq!(description.notes(), vec!["（见《白石道人歌曲》卷四）".to_string()]);
+    assert_eq!(description.quotes().len(), 1);
+    assert!(description.quotes()[0].starts_with("辛亥之冬"));
+    let links = description.links();
+    assert_eq!(links[0], ("姜夔".to_string(), "@姜夔".to_string()));
+    assert_eq!(links[1], ("石湖".to_string(), "#范成大".to_string()));
+    assert!(!description.text().contains('\t'));
+
+    assert!(anxiang.notes.is_none());
+    let lanlingwang = cipai_list.iter().find(|c| c.names[0] == "兰陵王").unwrap();
+    assert_eq!(lanlingwang.notes.as_ref().unwrap().text(), "四仄声领格字皆用去声。");
+
+    let langtaosha = cipai_list.iter().find(|c| c.names[0] == "浪淘沙").unwrap();
+    assert!(langtaosha.description.as_ref().unwrap().emphases().contains(&"激越凄壮".to_string()));
+}
+
+#[test]
+fn test_parse_category() {
+    let cipai_list = parse_cipai(&builtin_cipai()).unwrap();
+    let category = |name: &str, variant: &str| cipai_list.iter()
+        .find(|c| c.names[0] == name && c.variant.as_deref() == Some(variant))
+        .unwrap()
+        .category;
+    assert_eq!(category("渔歌子", "定格"), Some(CiPaiCategory::PingYun));
+    assert_eq!(category("调笑令", "定格"), Some(CiPaiCategory::ZhuanHuan));
+    // 格律上的类别覆盖所在类别
+    assert_eq!(category("调笑令", "变格"), Some(CiPaiCategory::ZeYun));
+    assert!(cipai_list.iter().all(|c| c.category.is_some()));
+}
+
+#[test]
+fn test_parse_unknown_category() {
+    let content = CUSTOM_CIPAI.replace("<名称>平韵格</名称>", "<名称>未知格</名称>");
+    assert!(parse_cipai(&content).unwrap().is_empty());
+}
+
+#[test]
+fn test_parse_chinese_number() {
+    assert_eq!(parse_chinese_number("廿六"), Some(26));
+    assert_eq!(parse_chinese_number("卅"), Some(30));
+    assert_eq!(parse_chinese_number("五十四"), Some(54));
+    assert_eq!(parse_chinese_number("一百三十四"), Some(134));
+    assert_eq!(parse_chinese_number("一百一"), Some(101));
+    assert_eq!(parse_chinese_number("二百四十"), Some(240));
+    assert_eq!(parse_chinese_number("两"), Some(2));
+    assert_eq!(parse_chinese_number("十六"), Some(16));
+    assert_eq!(parse_chinese_number("字"), None);
+}
+
+/// 所述的字数及平韵、仄韵、未写平仄的韵数
+fn stated_counts(text: &str) -> Vec<(usize, Option<[usize; 3]>)> {
+    parse_stated_counts(text).into_iter()
+        .map(|s| (s.chars, s.rhymes.map(|r| [r.ping, r.ze, r.other])))
+        .collect()
+}
+
+#[test]
+fn test_parse_stated_counts() {
+    assert_eq!(stated_counts("唐教坊曲，廿六字，三平韵。例用对句起。"), vec![(26, Some([3, 0, 0]))]);
+    assert_eq!(stated_counts("五十四字，前后片各四平韵，多作激越凄壮之音。"), vec![(54, Some([8, 0, 0]))]);
+    assert_eq!(stated_counts("四十四字，前后片各两仄韵，两平韵，平仄递转。"), vec![(44, Some([4, 4, 0]))]);
+    assert_eq!(stated_counts("共一百三十四字，分三段，第一、二段各四仄韵，第三段两仄韵。"), vec![(134, Some([0, 10, 0]))]);
+    assert_eq!(stated_counts("二百四十字，分四段，每段各四仄韵。"), vec![(240, Some([0, 16, 0]))]);
+    // 以顿号连写的几段
+    assert_eq!(stated_counts("一百四十四字，分三段，前段、中段四仄韵，后段五仄韵。"), vec![(144, Some([0, 13, 0]))]);
+    // 指明某一片的分句不沿用前面的倍数
+    assert_eq!(stated_counts("一百字，前后片各四平韵，后片第四句为上一、下四之句法，必须押一同部仄韵。"),
+               vec![(100, Some([8, 1, 0]))]);
+    assert_eq!(stated_counts("六十字，前后片各四韵。"), vec![(60, Some([0, 0, 8]))]);
+    // 其后句子中的韵数说的是别的格式
+    assert_eq!(stated_counts("四十四字，前后片各三平韵。《乐章集》增两字，后片转用两仄韵，两平韵。"),
+               vec![(44, Some([6, 0, 0]))]);
+    assert_eq!(stated_counts("一百字，前后片各四平韵，亦有于两结三字豆处增叶一韵者。"), vec![(100, Some([8, 0, 0]))]);
+    assert_eq!(stated_counts("两结句各添二字，第三字宜用去声。"), vec![]);
+    let stated = parse_stated_counts("又名《陇头月》。四十九字，前后片各三平韵。");
+    assert_eq!(stated[0].text, "四十九字，前后片各三平韵");
+}
+
+#[test]
+fn test_stated_counts_match_meter() {
+    let cipai_list = parse_cipai(&builtin_cipai()).unwrap();
+    let nangezi = cipai_list.iter().find(|c| c.names[0] == "南歌子").unwrap();
+    assert_eq!(nangezi.char_count, Some(26));
+    assert_eq!(nangezi.rhyme_count, Some(RhymeCounts { ping: 3, ze: 0, other: 0 }));
+    assert!(nangezi.count_mismatches().is_empty());
+    // 说明只写了单调的字数
+    let shuangdiao = cipai_list.iter()
+        .find(|c| c.names[0] == "南歌子" && c.variant.as_deref() == Some("双调"))
+        .unwrap();
+    assert_eq!(shuangdiao.char_count, None);
+    assert!(shuangdiao.count_mismatches().is_empty());
+
+    let yebanyue = cipai_list.iter().find(|c| c.names[0] == "夜半乐").unwrap();
+    assert_eq!(yebanyue.rhyme_count, Some(RhymeCounts { ping: 0, ze: 13, other: 0 }));
+    assert!(yebanyue.count_mismatches().is_empty());
+
+    // 定格只写了平韵，字数相同、兼用仄韵的格二、格三不是所述的格式
+    let liuzhou: Vec<&CiPai> = cipai_list.iter().filter(|c| c.names[0] == "六州歌头").collect();
+    assert_eq!(liuzhou[0].rhyme_count, Some(RhymeCounts { ping: 16, ze: 0, other: 0 }));
+    assert!(liuzhou[0].count_mismatches().is_empty());
+    assert!(liuzhou[1..].iter().all(|c| c.char_count.is_none() && c.count_mismatches().is_empty()));
+}
+
+#[test]
+fn test_stated_counts_attribution() {
+    let xml = |description: &str| format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<唐宋词格律>
+	<类别>
+		<名称>平韵格</名称>
+		<词牌>
+			<名称>自度曲</名称>
+			<说明><段落>{}</段落></说明>
+			<正文>
+				<格律 说明="定格"><![CDATA[＋│－－％，
+＋－－││－％。
+＋│－－％。]]></格律>
+				<格律 说明="变格"><![CDATA[＋│－－％，
+＋－－││－％。
+＋－－││－％。]]></格律>
+			</正文>
+		</词牌>
+	</类别>
+</唐宋词格律>
+"#, description);
+    // 先写的格式与格律字数不同，后写的与定格相同
+    let result = parse_cipai_with_diagnostics(&xml("初见某集，二十字。兹以某词为准，十四字，三平韵。")).unwrap();
+    assert_eq!(result.cipai[0].char_count, Some(14));
+    assert_eq!(result.unattributed_counts.len(), 1);
+    assert_eq!(result.unattributed_counts[0].0, "自度曲");
+    assert_eq!(result.unattributed_counts[0].1.chars, 20);
+
+    // 各格式分别对应字数相同的格律，字数都不同的第一种格式为定格
+    let result = parse_cipai_with_diagnostics(&xml("二十字，三平韵。又有十六字体，三平韵。")).unwrap();
+    assert_eq!(result.cipai[0].char_count, Some(20));
+    assert_eq!(result.cipai[0].count_mismatches(), vec!["说明为20字，格律为14字"]);
+    assert_eq!(result.cipai[1].char_count, Some(16));
+    assert!(result.unattributed_counts.is_empty());
+
+    // 平韵、仄韵分别比较
+    let result = parse_cipai_with_diagnostics(&xml("十四字，两平韵，一仄韵。")).unwrap();
+    assert_eq!(result.cipai[0].count_mismatches(), vec!["说明为2平韵，格律为3平韵", "说明为1仄韵，格律为0仄韵"]);
+}
+
+const INVALID_CIPAI: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<唐宋词格律>
+	<类别>
+		<名称>平韵格</名称>
+		<词牌>
+			<名称>测试调</名称>
+			<正文>
+				<格律 说明="定格"><![CDATA[－－││－－％，
+＋│－－Ｘ│＊。]]></格律></正文>
+			<正文>
+				<格律 说明="别格"><![CDATA[－－（││－－％，
+＋│－－││＊。]]></格律></正文>
+			<正文>
+				<格律 说明="格三"><![CDATA[－－││－－％，
+＋│－－││＊。]]></格律></正文>
+		</词牌>
+	</类别>
+</唐宋词格律>
+"#;
+
+#[test]
+fn test_parse_diagnostics() {
+    let result = parse_cipai_with_diagnostics(INVALID_CIPAI).unwrap();
+    // 只忽略出错的格律，同一词牌的其他格律照常解析
+    assert_eq!(result.cipai.len(), 1);
+    assert_eq!(result.cipai[0].variant.as_deref(), Some("格三"));
+    assert_eq!(result.diagnostics.len(), 2);
+
+    let unknown_char = &result.diagnostics[0];
+    assert_eq!(unknown_char.name, "测试调");
+    assert_eq!(unknown_char.variant.as_deref(), Some("定格"));
+    assert_eq!(unknown_char.line, Some(9));
+    assert_eq!(unknown_char.char, Some('Ｘ'));
+
+    let unclosed = &result.diagnostics[1];
+    assert_eq!(unclosed.variant.as_deref(), Some("别格"));
+    assert_eq!(unclosed.line, Some(11));
+    assert_eq!(unclosed.char, Some('（'));
+
+    assert_eq!(parse_cipai(INVALID_CIPAI).unwrap().len(), 1);
+    assert!(parse_cipai_with_diagnostics(INVALID_CIPAI).unwrap().into_strict().is_err());
+}
+
+#[test]
+fn test_builtin_cipai_has_no_diagnostics() {
+    let result = parse_cipai_with_diagnostics(&builtin_cipai()).unwrap();
+    assert!(result.diagnostics.is_empty());
+    assert!(result.warnings.is_empty());
+}
+
+#[test]
+fn test_parse_ru_rhyme() {
+    let cipai_list = parse_cipai(&builtin_cipai()).unwrap();
+    let ru_rhyme = |name: &str, variant: &str| cipai_list.iter()
+        .find(|c| c.names[0] == name && c.variant.as_deref() == Some(variant))
+        .unwrap()
+        .meter.ru_rhyme;
+    assert!(ru_rhyme("念奴娇", "定格"));
+    assert!(ru_rhyme("满江红", "定格"));
+    assert!(ru_rhyme("多丽", "变格（入声韵）"));
+    // 平韵格不受影响
+    assert!(!ru_rhyme("念奴娇", "平韵格"));
+    // 变体写明用上、去声韵
+    assert!(!ru_rhyme("兰陵王", "变格（上、去声韵）"));
+    assert!(!ru_rhyme("渔歌子", "定格"));
+}
+
+#[test]
+fn test_parse_qu() {
+    let cipai_list = parse_cipai(&builtin_cipai()).unwrap();
+    let taichangyin = cipai_list.iter().find(|c| c.names[0] == "太常引").unwrap();
+    let line = &taichangyin.meter.lines[3];
+    assert_eq!(line[5].tone, MeterToneType::Qu);
+    assert_eq!(line[5].to_string(), "去");
+    assert_eq!(taichangyin.meter.lines.iter().flatten().filter(|t| t.tone == MeterToneType::Qu).count(), 2);
+}
+
+#[test]
+fn test_parse_rhyme_scheme() {
+    let cipai_list = parse_cipai(&builtin_cipai()).unwrap();
+    let find = |name: &str| cipai_list.iter().find(|c| c.names[0] == name).unwrap();
+
+    // 换韵，每个字母为一个韵段
+    let pusaman = &find("菩萨蛮").meter;
+    let slots: Vec<(char, BasicTone, usize)> = pusaman.rhyme_scheme.slots.iter()
+        .map(|s| (s.marker, s.tone.clone(), s.segment)).collect();
+    assert_eq!(slots, vec![('a', BasicTone::Ze, 0), ('b', BasicTone::Ping, 1),
+                           ('c', BasicTone::Ze, 2), ('d', BasicTone::Ping, 3)]);
+    assert!(pusaman.rhyme_scheme.same_group.is_empty());
+    assert_eq!(pusaman.lines[2].last().unwrap().rhyme, Some(1));
+
+    // 平韵与仄韵同部
+    let xijiangyue = &find("西江月").meter;
+    assert_eq!(xijiangyue.rhyme_scheme.slots[1], RhymeSlot { marker: '＊', tone: BasicTone::Ze, segment: 0 });
+    assert_eq!(xijiangyue.rhyme_scheme.same_group, vec![(0, 1)]);
+    assert_eq!(xijiangyue.rhyme_scheme.segment_count(), 1);
+    assert_eq!(xijiangyue.rhyme_scheme.ping_ze_link, Some(PingZeLink::TongYe));
+    assert_eq!(xijiangyue.rhyme_scheme.link_name(0, 1), "通叶");
+
+    // 错叶，说明未写明同部，仄韵换韵，不须与平韵同部
+    let dingfengbo = &find("定风波").meter;
+    assert_eq!(dingfengbo.rhyme_scheme.slots.len(), 4);
+    assert!(dingfengbo.rhyme_scheme.same_group.is_empty());
+    assert_eq!(dingfengbo.rhyme_scheme.segment_count(), 4);
+    // 仄韵三换，平韵贯穿全篇，每个仄韵段都与平韵段相接
+    assert_eq!(dingfengbo.rhyme_scheme.adjacent_segments(&dingfengbo.lines), vec![(0, 1), (0, 2), (0, 3)]);
+
+    // “或同部平仄互叶”是两可之说，换叶的格三不须同部
+    let liuzhou = cipai_list.iter()
+        .find(|c| c.names[0] == "六州歌头" && c.variant.as_deref().is_some_and(|v| v.starts_with("格三")))
+        .unwrap();
+    assert_eq!(liuzhou.category, Some(CiPaiCategory::CuoYe));
+    assert_eq!(liuzhou.meter.rhyme_scheme.ping_ze_link, None);
+}
+
+#[test]
+fn test_parse_cuoye_same_group() {
+    let xml = |description: &str| format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<唐宋词格律>
+	<类别>
+		<名称>平仄韵错叶格</名称>
+		<词牌>
+			<名称>自度曲</名称>
+			<说明><段落>{}</段落></说明>
+			<正文>
+				<格律 说明="定格"><![CDATA[＋│－％，
+＋－－│＊。
+＋││－％。]]></格律>
+			</正文>
+		</词牌>
+	</类别>
+</唐宋词格律>
+"#, description);
+    // 说明中写明同部错叶，平韵与仄韵须同部
+    let cipai = parse_cipai(&xml("十七字，两平韵，一仄韵，同部错叶。")).unwrap();
+    let scheme = &cipai[0].meter.rhyme_scheme;
+    assert_eq!(scheme.ping_ze_link, Some(PingZeLink::CuoYe));
+    assert_eq!(scheme.same_group, vec![(0, 1)]);
+    assert_eq!(scheme.link_name(0, 1), "错叶");
+
+    let cipai = parse_cipai(&xml("十七字，两平韵，一仄韵，不同部错叶。")).unwrap();
+    assert!(cipai[0].meter.rhyme_scheme.same_group.is_empty());
+}
@@ -1,4 +1,6 @@
 pub mod rhyme_parser;
 #[cfg(test)]
 mod rhyme_parser_test;
-pub mod cipai_parser;
\ No newline at end of file
+pub mod cipai_parser;
+#[cfg(test)]
+mod cipai_parser_test;
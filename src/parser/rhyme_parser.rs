@@ -1,22 +1,92 @@
+use crate::core::charmap::CharMap;
 use crate::core::rhyme::{Rhyme, RhymeDict, RhymeId};
-use crate::core::tone::BasicTone;
+use crate::core::tone::ShengDiao;
 use anyhow::{bail, Context, Result};
 use serde_json::Value;
+use std::fs;
+use std::path::Path;
 use std::sync::Arc;
 
+/// 韵书 JSON 文件格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RhymeDictFormat {
+    /// 声部 -> 韵目 -> 字，同平水韵文件
+    Pingshui,
+    /// 韵部 -> 声调 -> 字，同词林正韵、中华新韵文件。仄声可分列上声、去声
+    Cilin,
+}
+
+const PINGSHUI_SHENGBU: [&str; 5] = ["上平声部", "下平声部", "上声部", "去声部", "入声部"];
+const CILIN_TONES: [&str; 7] = ["平声", "平", "仄声", "仄", "上声", "去声", "入声"];
+
+/// 根据 JSON 结构识别韵书格式
+pub fn detect_dict_format(content: &str) -> Result<RhymeDictFormat> {
+    let json: Value = serde_json::from_str(content).context("韵书文件不是合法的 JSON")?;
+    let top = json.as_object().context("韵书文件顶层应为对象")?;
+    if top.is_empty() {
+        bail!("韵书文件为空");
+    }
+    if top.keys().all(|k| PINGSHUI_SHENGBU.contains(&k.as_str())) {
+        return Ok(RhymeDictFormat::Pingshui);
+    }
+    let is_cilin = top.values().all(|v| {
+        v.as_object()
+            .is_some_and(|m| m.keys().all(|k| CILIN_TONES.contains(&k.as_str())))
+    });
+    if is_cilin {
+        return Ok(RhymeDictFormat::Cilin);
+    }
+    bail!("无法识别韵书格式：顶层应为声部名称（{}），或韵部下为声调名称（{}）",
+        PINGSHUI_SHENGBU.join("、"), CILIN_TONES.join("、"));
+}
+
+/// 解析韵书内容，未指定格式时自动识别
+pub fn parse_rhyme_dict(content: &str, format: Option<RhymeDictFormat>) -> Result<RhymeDict> {
+    let format = match format {
+        Some(f) => f,
+        None => detect_dict_format(content)?,
+    };
+    match format {
+        RhymeDictFormat::Pingshui => parse_pingshui(content),
+        RhymeDictFormat::Cilin => parse_cilin(content),
+    }
+}
+
+/// 从文件加载韵书，未指定格式时自动识别
+pub fn load_rhyme_dict_file(path: impl AsRef<Path>, format: Option<RhymeDictFormat>) -> Result<RhymeDict> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("无法读取韵书文件: {}", path.display()))?;
+    parse_rhyme_dict(&content, format)
+        .with_context(|| format!("韵书文件解析失败: {}", path.display()))
+}
+
+/// 从文件加载异体字对照表，格式同 `data/char/yiti.txt`
+pub fn load_yiti_file(path: impl AsRef<Path>) -> Result<CharMap> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("无法读取异体字文件: {}", path.display()))?;
+    CharMap::parse(&content)
+        .with_context(|| format!("异体字文件解析失败: {}", path.display()))
+}
+
+fn next_rhyme_id(id: RhymeId) -> Result<RhymeId> {
+    id.checked_add(1).with_context(|| format!("韵部数量过多，最多支持 {} 个", RhymeId::MAX))
+}
+
 pub fn parse_pingshui(content: &str) -> Result<RhymeDict> {
     let json: Value = serde_json::from_str(content)?;
     let json_format_err = "平水韵文件格式错误";
     let mut rhymes= vec![];
-    let mut rhyme_chars = vec![];
+    let mut rhyme_chars: Vec<Vec<char>> = vec![];
     let mut cur_rhyme_id: RhymeId = 0;
     for (shengbu, chars_map) in json.as_object().context(json_format_err)? {
-        let tone = if shengbu == "上平声部" || shengbu == "下平声部" {
-            BasicTone::Ping
-        } else if shengbu == "上声部" || shengbu == "去声部" || shengbu == "入声部" {
-            BasicTone::Ze
-        } else {
-            bail!("平水韵文件中错误声部名称: {}", shengbu);
+        let sheng_diao = match shengbu.as_str() {
+            "上平声部" | "下平声部" => ShengDiao::Ping,
+            "上声部" => ShengDiao::Shang,
+            "去声部" => ShengDiao::Qu,
+            "入声部" => ShengDiao::Ru,
+            _ => bail!("平水韵文件中错误声部名称: {}", shengbu),
         };
         for (name, raw_chars) in chars_map.as_object().context(json_format_err)? {
             // insert rhyme
@@ -24,7 +94,8 @@ pub fn parse_pingshui(content: &str) -> Result<RhymeDict> {
                 id: cur_rhyme_id,
                 name: name.clone(),
                 group: None, // 平水韵为诗韵，平仄不在统一韵部，所以设置为空
-                tone: tone.clone(),
+                tone: sheng_diao.basic(),
+                sheng_diao,
             };
             rhymes.push(Arc::new(rhyme));
 
@@ -38,9 +109,12 @@ pub fn parse_pingshui(content: &str) -> Result<RhymeDict> {
                 }
                 chars.push(str.chars().next().unwrap());
             }
+            if chars.is_empty() {
+                bail!("韵部中没有字: {}", name);
+            }
             rhyme_chars.push(chars);
 
-            cur_rhyme_id += 1;
+            cur_rhyme_id = next_rhyme_id(cur_rhyme_id)?;
         }
     }
     RhymeDict::new(rhyme_chars, rhymes)
@@ -51,25 +125,20 @@ pub fn parse_cilin(content: &str) -> Result<RhymeDict> {
     let json: Value = serde_json::from_str(content)?;
     let json_format_err = "文件格式错误";
     let mut rhymes= vec![];
-    let mut rhyme_chars = vec![];
+    let mut rhyme_chars: Vec<Vec<char>> = vec![];
+    let mut qu_chars = vec![];
     let mut cur_rhyme_id: RhymeId = 0;
     for (group, tone_map) in json.as_object().context(json_format_err)? {
+        // 分列上声、去声时，与仄声同押一韵，去声字另记以检查去声
+        let mut shang_qu_rhyme: Option<usize> = None;
         for (tone_str, raw_chars) in tone_map.as_object().context(json_format_err)? {
-            let tone = if tone_str == "平声" || tone_str == "平" {
-                BasicTone::Ping
-            } else if tone_str == "仄声" || tone_str == "仄" || tone_str == "入声" { // 不对入声单独处理
-                BasicTone::Ze
-            } else {
-                bail!("文件中错误声部名称: {}", tone_str);
+            // 词林正韵的仄声不分上去
+            let sheng_diao = match tone_str.as_str() {
+                "平声" | "平" => ShengDiao::Ping,
+                "仄声" | "仄" | "上声" | "去声" => ShengDiao::ShangQu,
+                "入声" => ShengDiao::Ru,
+                _ => bail!("文件中错误声部名称: {}", tone_str),
             };
-            // insert rhyme
-            let rhyme = Rhyme {
-                id: cur_rhyme_id,
-                name: group.clone(),
-                group: Some(group.clone()),
-                tone: tone.clone(),
-            };
-            rhymes.push(Arc::new(rhyme));
 
             // insert chars
             let raw_chars_arr = raw_chars.as_array().context(json_format_err)?;
@@ -81,10 +150,37 @@ pub fn parse_cilin(content: &str) -> Result<RhymeDict> {
                 }
                 chars.push(str.chars().next().unwrap());
             }
+            if chars.is_empty() {
+                bail!("韵部中没有字: {} {}", group, tone_str);
+            }
+            if tone_str == "去声" {
+                qu_chars.extend(chars.iter().copied());
+            }
+            if sheng_diao == ShengDiao::ShangQu && let Some(i) = shang_qu_rhyme {
+                for c in chars {
+                    if !rhyme_chars[i].contains(&c) {
+                        rhyme_chars[i].push(c);
+                    }
+                }
+                continue;
+            }
+            if sheng_diao == ShengDiao::ShangQu {
+                shang_qu_rhyme = Some(rhyme_chars.len());
+            }
             rhyme_chars.push(chars);
 
-            cur_rhyme_id += 1;
+            // insert rhyme
+            let rhyme = Rhyme {
+                id: cur_rhyme_id,
+                name: group.clone(),
+                group: Some(group.clone()),
+                tone: sheng_diao.basic(),
+                sheng_diao,
+            };
+            rhymes.push(Arc::new(rhyme));
+
+            cur_rhyme_id = next_rhyme_id(cur_rhyme_id)?;
         }
     }
-    RhymeDict::new(rhyme_chars, rhymes)
+    Ok(RhymeDict::new(rhyme_chars, rhymes)?.with_qu_chars(qu_chars))
 }
\ No newline at end of file
@@ -1,11 +1,18 @@
-use crate::core::tone::BasicTone;
-use crate::parser::rhyme_parser::{parse_pingshui, parse_cilin};
+use std::fs;
+use crate::core::charmap::CharMap;
+use crate::core::tone::{BasicTone, ShengDiao};
+use crate::parser::rhyme_parser::{parse_pingshui, parse_cilin, detect_dict_format,
+    load_rhyme_dict_file, load_yiti_file, parse_rhyme_dict, RhymeDictFormat};
+
+fn read_data(file_path: &str) -> String {
+    fs::read_to_string(file_path).expect("Should read data file")
+}
 
 #[test]
 fn test_parse_pingshui_success() {
     // Test parsing the Pingshui_Rhyme.json file
     let file_path = "data/rhyme/Pingshui_Rhyme.json";
-    let result = parse_pingshui(file_path);
+    let result = parse_pingshui(&read_data(file_path));
 
     assert!(result.is_ok(), "Failed to parse Pingshui_Rhyme.json: {:?}", result.err());
 
@@ -25,7 +32,7 @@ fn test_parse_pingshui_success() {
 #[test]
 fn test_parse_pingshui_rhyme_structure() {
     let file_path = "data/rhyme/Pingshui_Rhyme.json";
-    let rhyme_dict = parse_pingshui(file_path).expect("Should parse successfully");
+    let rhyme_dict = parse_pingshui(&read_data(file_path)).expect("Should parse successfully");
 
     // Test that different tone sections are parsed correctly
     // '东' is in 上平声部 (Ping)
@@ -42,7 +49,7 @@ fn test_parse_pingshui_rhyme_structure() {
 #[test]
 fn test_parse_pingshui_char_lookup() {
     let file_path = "data/rhyme/Pingshui_Rhyme.json";
-    let rhyme_dict = parse_pingshui(file_path).expect("Should parse successfully");
+    let rhyme_dict = parse_pingshui(&read_data(file_path)).expect("Should parse successfully");
 
     // Test character '冬' from "二冬" rhyme
     let rhymes_for_dong = rhyme_dict.get_rhymes_by_char(&'冬');
@@ -58,7 +65,7 @@ fn test_parse_pingshui_char_lookup() {
 #[test]
 fn test_parse_pingshui_rhyme_id_lookup() {
     let file_path = "data/rhyme/Pingshui_Rhyme.json";
-    let rhyme_dict = parse_pingshui(file_path).expect("Should parse successfully");
+    let rhyme_dict = parse_pingshui(&read_data(file_path)).expect("Should parse successfully");
 
     // Get rhyme by ID (first rhyme should be ID 0)
     let rhyme_0 = rhyme_dict.get_rhymes_by_char(&'东').first();
@@ -76,14 +83,14 @@ fn test_parse_pingshui_rhyme_id_lookup() {
 
 #[test]
 fn test_parse_pingshui_invalid_file() {
-    let result = parse_pingshui("non_existent_file.json");
+    let result = load_rhyme_dict_file("non_existent_file.json", Some(RhymeDictFormat::Pingshui));
     assert!(result.is_err(), "Should fail for non-existent file");
 }
 
 #[test]
 fn test_parse_pingshui_tone_detection() {
     let file_path = "data/rhyme/Pingshui_Rhyme.json";
-    let rhyme_dict = parse_pingshui(file_path).expect("Should parse successfully");
+    let rhyme_dict = parse_pingshui(&read_data(file_path)).expect("Should parse successfully");
 
     // Verify that all characters from "一东" are in Ping tone
     let rhyme_0 = rhyme_dict.get_rhymes_by_char(&'东').first().unwrap();
@@ -95,7 +102,7 @@ fn test_parse_pingshui_tone_detection() {
 fn test_parse_cilin_success() {
     // Test parsing the Cilin_Rhyme.json file
     let file_path = "data/rhyme/Cilin_Rhyme.json";
-    let result = parse_cilin(file_path);
+    let result = parse_cilin(&read_data(file_path));
 
     assert!(result.is_ok(), "Failed to parse Cilin_Rhyme.json: {:?}", result.err());
 
@@ -116,7 +123,7 @@ fn test_parse_cilin_success() {
 #[test]
 fn test_parse_cilin_rhyme_structure() {
     let file_path = "data/rhyme/Cilin_Rhyme.json";
-    let rhyme_dict = parse_cilin(file_path).expect("Should parse successfully");
+    let rhyme_dict = parse_cilin(&read_data(file_path)).expect("Should parse successfully");
 
     // Test that different tone sections are parsed correctly
     // '东' is in 第一部平声 (Ping)
@@ -132,7 +139,7 @@ fn test_parse_cilin_rhyme_structure() {
 #[test]
 fn test_parse_cilin_char_lookup() {
     let file_path = "data/rhyme/Cilin_Rhyme.json";
-    let rhyme_dict = parse_cilin(file_path).expect("Should parse successfully");
+    let rhyme_dict = parse_cilin(&read_data(file_path)).expect("Should parse successfully");
 
     // Test character '东' from "第一部" rhyme (平声)
     let rhymes_for_dong = rhyme_dict.get_rhymes_by_char(&'东');
@@ -146,10 +153,38 @@ fn test_parse_cilin_char_lookup() {
     assert!(all_rhymes_have_group, "All Cilin rhymes should have a group");
 }
 
+#[test]
+fn test_traditional_char_lookup() {
+    let rhyme_dict = parse_cilin(&read_data("data/rhyme/Cilin_Rhyme.json")).expect("Should parse successfully");
+    assert_eq!(rhyme_dict.dict_char(&'東'), '东');
+    assert_eq!(rhyme_dict.get_rhymes_by_char(&'東'), rhyme_dict.get_rhymes_by_char(&'东'));
+    assert!(rhyme_dict.contains_char(&'風'));
+    // 韵书收录的字不转换
+    assert_eq!(rhyme_dict.dict_char(&'东'), '东');
+}
+
+#[test]
+fn test_variant_char_lookup() {
+    let rhyme_dict = parse_cilin(&read_data("data/rhyme/Cilin_Rhyme.json")).expect("Should parse successfully");
+    assert_eq!(rhyme_dict.variant_of(&'峯'), Some('峰'));
+    assert_eq!(rhyme_dict.get_rhymes_by_char(&'峯'), rhyme_dict.get_rhymes_by_char(&'峰'));
+    // 异体字的正字为繁体字时按简体字查找
+    assert_eq!(rhyme_dict.dict_char(&'綫'), rhyme_dict.dict_char(&'線'));
+    assert!(rhyme_dict.contains_char(&'綫'));
+    // 繁体字不算异体字
+    assert_eq!(rhyme_dict.variant_of(&'東'), None);
+    assert!(!rhyme_dict.contains_char(&'𡶶'));
+
+    let variants = load_yiti_file("data/char/yiti.txt").expect("Should load variant file");
+    let rhyme_dict = rhyme_dict.with_variants(variants).with_variants(CharMap::parse("𡶶 峰").unwrap());
+    assert_eq!(rhyme_dict.variant_of(&'𡶶'), Some('峰'));
+    assert!(load_yiti_file("data/rhyme/Cilin_Rhyme.json").is_err());
+}
+
 #[test]
 fn test_parse_cilin_rhyme_id_lookup() {
     let file_path = "data/rhyme/Cilin_Rhyme.json";
-    let rhyme_dict = parse_cilin(file_path).expect("Should parse successfully");
+    let rhyme_dict = parse_cilin(&read_data(file_path)).expect("Should parse successfully");
 
     // Get rhyme by ID (first rhyme should be ID 0)
     let rhyme_0 = rhyme_dict.get_rhymes_by_char(&'东').first();
@@ -168,14 +203,14 @@ fn test_parse_cilin_rhyme_id_lookup() {
 
 #[test]
 fn test_parse_cilin_invalid_file() {
-    let result = parse_cilin("non_existent_file.json");
+    let result = load_rhyme_dict_file("non_existent_file.json", Some(RhymeDictFormat::Cilin));
     assert!(result.is_err(), "Should fail for non-existent file");
 }
 
 #[test]
 fn test_parse_cilin_tone_detection() {
     let file_path = "data/rhyme/Cilin_Rhyme.json";
-    let rhyme_dict = parse_cilin(file_path).expect("Should parse successfully");
+    let rhyme_dict = parse_cilin(&read_data(file_path)).expect("Should parse successfully");
 
     // Verify that rhyme 0 (first rhyme) has correct structure
     let rhyme_0 = rhyme_dict.get_rhymes_by_char(&'东').first().unwrap();
@@ -185,10 +220,36 @@ fn test_parse_cilin_tone_detection() {
     assert_eq!(rhyme_0.group, Some("第一部".to_string()), "First rhyme should be in '第一部' group");
 }
 
+#[test]
+fn test_parse_pingshui_sheng_diao() {
+    let rhyme_dict = parse_pingshui(&read_data("data/rhyme/Pingshui_Rhyme.json")).unwrap();
+    assert_eq!(rhyme_dict.get_rhymes_by_char(&'东')[0].sheng_diao, ShengDiao::Ping);
+    assert_eq!(rhyme_dict.get_rhymes_by_char(&'董')[0].sheng_diao, ShengDiao::Shang);
+    assert_eq!(rhyme_dict.get_rhymes_by_char(&'冻')[0].sheng_diao, ShengDiao::Qu);
+    let wu = rhyme_dict.get_rhymes_by_char(&'屋')[0].clone();
+    assert_eq!(wu.sheng_diao, ShengDiao::Ru);
+    assert_eq!(wu.tone, BasicTone::Ze);
+    assert!(rhyme_dict.has_ru_sheng());
+}
+
+#[test]
+fn test_parse_cilin_sheng_diao() {
+    let rhyme_dict = parse_cilin(&read_data("data/rhyme/Cilin_Rhyme.json")).unwrap();
+    // 词林正韵仄声不分上去，入声单列
+    assert_eq!(rhyme_dict.get_rhymes_by_char(&'董')[0].sheng_diao, ShengDiao::ShangQu);
+    let wu = rhyme_dict.get_rhymes_by_char(&'屋')[0].clone();
+    assert_eq!(wu.sheng_diao, ShengDiao::Ru);
+    assert_eq!(wu.tone, BasicTone::Ze);
+    assert!(rhyme_dict.has_ru_sheng());
+
+    let xinyun = parse_cilin(&read_data("data/rhyme/Xinyun_Rhyme.json")).unwrap();
+    assert!(!xinyun.has_ru_sheng());
+}
+
 #[test]
 fn test_parse_cilin_group_consistency() {
     let file_path = "data/rhyme/Cilin_Rhyme.json";
-    let rhyme_dict = parse_cilin(file_path).expect("Should parse successfully");
+    let rhyme_dict = parse_cilin(&read_data(file_path)).expect("Should parse successfully");
 
     // Test that characters in the same group can have different tones
     // For example, a character might appear in both 平声 and 仄声 of the same group
@@ -206,3 +267,63 @@ fn test_parse_cilin_group_consistency() {
                 ch, rhyme_0.group);
     }
 }
+
+#[test]
+fn test_detect_dict_format() {
+    let pingshui = read_data("data/rhyme/Pingshui_Rhyme.json");
+    let cilin = read_data("data/rhyme/Cilin_Rhyme.json");
+    let xinyun = read_data("data/rhyme/Xinyun_Rhyme.json");
+    assert_eq!(detect_dict_format(&pingshui).unwrap(), RhymeDictFormat::Pingshui);
+    assert_eq!(detect_dict_format(&cilin).unwrap(), RhymeDictFormat::Cilin);
+    assert_eq!(detect_dict_format(&xinyun).unwrap(), RhymeDictFormat::Cilin);
+}
+
+#[test]
+fn test_detect_dict_format_invalid() {
+    assert!(detect_dict_format("not json").is_err());
+    assert!(detect_dict_format("[]").is_err());
+    assert!(detect_dict_format("{}").is_err());
+    let err = detect_dict_format(r#"{"第一部": {"阴平": ["东"]}}"#).unwrap_err();
+    assert!(err.to_string().contains("无法识别韵书格式"), "Unexpected error: {}", err);
+}
+
+#[test]
+fn test_load_rhyme_dict_file_auto_detect() {
+    let rhyme_dict = load_rhyme_dict_file("data/rhyme/Cilin_Rhyme.json", None)
+        .expect("Should load successfully");
+    let rhymes = rhyme_dict.get_rhymes_by_char(&'东');
+    assert_eq!(rhymes[0].group, Some("第一部".to_string()));
+}
+
+#[test]
+fn test_parse_rhyme_dict_custom_content() {
+    let content = r#"{"第一部": {"平声": ["东", "同"], "仄声": ["董"]}}"#;
+    let rhyme_dict = parse_rhyme_dict(content, None).expect("Should parse successfully");
+    assert_eq!(rhyme_dict.get_rhymes_by_char(&'董')[0].tone, BasicTone::Ze);
+
+    let err = parse_rhyme_dict(r#"{"第一部": {"平声": ["东风"]}}"#, None).err().unwrap();
+    assert!(err.to_string().contains("不是单字"), "Unexpected error: {}", err);
+    assert!(parse_rhyme_dict(r#"{"第一部": {"平声": []}}"#, None).is_err());
+    assert!(parse_rhyme_dict(content, Some(RhymeDictFormat::Pingshui)).is_err());
+}
+
+#[test]
+fn test_parse_cilin_shang_qu() {
+    // 分列上声、去声时仍同押一韵，另记去声字
+    let content = r#"{"第一部": {"平声": ["东"], "上声": ["董", "动"], "去声": ["送", "动"]}}"#;
+    assert_eq!(detect_dict_format(content).unwrap(), RhymeDictFormat::Cilin);
+    let rhyme_dict = parse_rhyme_dict(content, None).expect("Should parse successfully");
+    assert!(rhyme_dict.has_qu_sheng());
+    let rhymes = rhyme_dict.get_rhymes_by_char(&'董');
+    assert_eq!(rhymes.len(), 1);
+    assert_eq!(rhymes[0].sheng_diao, ShengDiao::ShangQu);
+    assert_eq!(rhymes, rhyme_dict.get_rhymes_by_char(&'送'));
+    assert_eq!(rhyme_dict.get_rhymes_by_char(&'动').len(), 1);
+    assert!(rhyme_dict.is_qu_sheng(&'送'));
+    assert!(rhyme_dict.is_qu_sheng(&'动'));
+    assert!(!rhyme_dict.is_qu_sheng(&'董'));
+
+    // 不分上去的词林正韵不检查去声
+    let rhyme_dict = parse_cilin(&read_data("data/rhyme/Cilin_Rhyme.json")).expect("Should parse successfully");
+    assert!(!rhyme_dict.has_qu_sheng());
+}